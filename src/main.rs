@@ -1,8 +1,9 @@
 use std::env;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Result};
+use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
 
 use parsers::input::InputParser;
+use writer::Format;
 
 mod writer;
 mod parsers;
@@ -13,19 +14,30 @@ fn io() -> (String, String) {
     let args: Vec<String> = env::args().collect();
     match args.len() {
         2 => (args[1].to_string(), "output.wav".to_string()),
-        3 => (args[1].to_string(), args[2].to_string()),
+        3.. => (args[1].to_string(), args[2].to_string()),
         _ => ("input.txt".to_string(), "output.wav".to_string()),
     }
 }
 
+/// read sample format, 16-bit PCM by default
+fn format() -> Result<Format> {
+    match env::args().nth(3) {
+        Some(arg) => Format::parse(&arg).ok_or_else(|| Error::new(
+            ErrorKind::InvalidInput, format!("invalid format, expected 8 | 16 | 24 | 32 | 32f, found {}", arg),
+        )),
+        None => Ok(Format::Int(16)),
+    }
+}
+
 fn main() -> Result<()> {
     let (input, output) = io();
+    let format = format()?;
 
     let rate = 12000;
     // maximum 6 notes at a time
     let max = 6;
 
-    let mut parser = InputParser::new(File::create(output)?, max, rate);
+    let mut parser = InputParser::new(File::create(output)?, format, max, rate);
     let reader = BufReader::new(File::open(input)?);
-    Ok(parser.write(reader.lines().flatten())?)
+    parser.write(reader.lines().map_while(Result::ok))
 }
@@ -1,7 +1,9 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::rc::Rc;
 
-use crate::stores::note::Chord;
+use crate::parsers::directive::parse_interval;
+use crate::parsers::note::NoteParser;
+use crate::stores::note::{Chord, Pitch};
 
 const CAP: u8 = b'(';
 const POP: u8 = b'<';
@@ -9,8 +11,13 @@ const FRONT: u8 = b'[';
 const CLEAR: u8 = b'{';
 const ROTATE: u8 = b'|';
 
-const RAISE: [u8; 2] = [b'^', b'8'];
-const LOWER: [u8; 2] = [b'_', b'8'];
+const RAISE: char = '^';
+const LOWER: char = '_';
+const DIATONIC: char = 'd';
+const INVERSION: char = 'i';
+const RETROGRADE: char = 'r';
+/// interval that is an octave, as in 8va and 8vb
+const OTTAVA: &str = "8";
 
 #[derive(PartialEq)]
 pub enum Cap {
@@ -20,29 +27,89 @@ pub enum Cap {
     Front(Rc<Chord>),
 }
 
+/// how a chord from captures is changed before it's played
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Transform {
+    /// up (or down) a number of semitones
+    Shift(i32),
+    /// up (or down) a number of degrees of the scale of the key signature
+    Step(i32),
+    /// the lowest note up an octave
+    Invert,
+}
+
 /// check if a line should be parsed as capture based on the first byte
 pub fn should_be_cap(byte: u8) -> bool {
     matches!(byte, CAP | POP | FRONT | CLEAR | ROTATE)
 }
 
-fn parse_scale(bytes: &[u8]) -> Option<f64> {
-    if bytes.ends_with(&LOWER) {
-        Some(0.5)
-    } else if bytes.ends_with(&RAISE) {
-        Some(2.0)
-    } else {
-        None
+/// closing character of the key of each capture instruction
+fn closing(prefix: u8) -> char {
+    match prefix {
+        CAP => ')',
+        POP => '>',
+        FRONT => ']',
+        CLEAR => '}',
+        _ => ROTATE as char,
+    }
+}
+
+/// parse the transforms after the key (e.g. `^3`, `_8`, `^2d`, `^M3`, `ii`, `r`)
+/// returns the transforms in order and whether the queue is played backwards
+fn parse_transforms(suffix: &str) -> Result<(Vec<Transform>, bool), String> {
+    let (mut transforms, mut retrograde) = (Vec::new(), false);
+    let mut rest = suffix;
+    while let Some(ch) = rest.chars().next() {
+        rest = &rest[ch.len_utf8()..];
+        match ch {
+            INVERSION => transforms.push(Transform::Invert),
+            RETROGRADE => retrograde = true,
+            RAISE | LOWER => {
+                let sign = if ch == RAISE { 1 } else { -1 };
+                // the interval goes until the next transform
+                let (interval, next) = rest.split_at(rest.find([RAISE, LOWER, INVERSION, RETROGRADE]).unwrap_or(rest.len()));
+                rest = next;
+                transforms.push(match interval.strip_suffix(DIATONIC).map(|steps| steps.parse::<i32>()) {
+                    Some(Ok(steps)) => Transform::Step(sign * steps),
+                    _ if interval == OTTAVA => Transform::Shift(sign * 12),
+                    _ => Transform::Shift(sign * parse_interval(interval)?),
+                });
+            }
+            _ => return Err(format!("invalid capture transform: {}", suffix)),
+        }
     }
+    Ok((transforms, retrograde))
+}
+
+/// apply a transform to every pitch of a chord
+fn transform(chord: &Chord, transform: Transform, note: &mut NoteParser) -> Result<Chord, String> {
+    let pitches = match transform {
+        Transform::Shift(semitones) => chord.pitches.iter().map(|p| note.shift(p, semitones)).collect::<Result<_, _>>()?,
+        Transform::Step(steps) => chord.pitches.iter().map(|p| note.step(p, steps)).collect::<Result<_, _>>()?,
+        Transform::Invert => {
+            let mut pitches: Vec<Pitch> = chord.pitches.clone();
+            // rests can't be the lowest note
+            if let Some(lowest) = pitches.iter_mut().filter(|p| p.frequency != 0.0).min_by(
+                |a, b| a.frequency.total_cmp(&b.frequency)
+            ) {
+                *lowest = note.shift(lowest, 12)?;
+            }
+            pitches
+        }
+    };
+    Ok(Chord { pitches, ..chord.clone() })
 }
 
 pub struct CaptureParser {
-    /// stores the captured chords
-    captures: HashMap<Rc<String>, VecDeque<Rc<Chord>>>,
+    /// stores the captured chords, and the transposition they were written with
+    captures: HashMap<Rc<String>, VecDeque<(Rc<Chord>, i32)>>,
     /// things to do upon update
     to_cap: HashSet<Rc<String>>,
     to_pop: HashSet<Rc<String>>,
     to_clear: HashSet<Rc<String>>,
     to_rotate: HashSet<Rc<String>>,
+    /// keys to pop and rotate from the back instead (retrograde)
+    reversed: HashSet<Rc<String>>,
 }
 
 impl CaptureParser {
@@ -53,17 +120,18 @@ impl CaptureParser {
             to_pop: HashSet::new(),
             to_clear: HashSet::new(),
             to_rotate: HashSet::new(),
+            reversed: HashSet::new(),
         }
     }
     /// push new key to capture upon update
     pub fn will_capture(&mut self, key: Rc<String>) {
         self.to_cap.insert(key);
     }
-    /// push a chord to captures and clear the keys to capture
-    pub fn capture(&mut self, chord: Rc<Chord>) {
+    /// push a chord written with a transposition to captures and clear the keys to capture
+    pub fn capture(&mut self, chord: Rc<Chord>, transpose: i32) {
         let captures = &mut self.captures;
         self.to_cap.drain().for_each(
-            |cap| captures.entry(cap).or_insert(VecDeque::new()).push_back(Rc::clone(&chord))
+            |cap| captures.entry(cap).or_default().push_back((Rc::clone(&chord), transpose))
         );
     }
     /// update the captures
@@ -73,47 +141,62 @@ impl CaptureParser {
         let pop = &self.to_pop;
         let clear = &self.to_clear;
         let rotate = &self.to_rotate;
+        let reversed = &self.reversed;
         // pop \ (shift ∪ clear)
-        pop.difference(&rotate).filter(
+        pop.difference(rotate).filter(
             |&k| !clear.contains(k)
-        ).for_each(|k| { captures.get_mut(k).unwrap().pop_front(); });
+        ).for_each(|k| {
+            let queue = captures.get_mut(k).unwrap();
+            if reversed.contains(k) { queue.pop_back(); } else { queue.pop_front(); }
+        });
         // shift \ clear
-        rotate.difference(&clear).for_each(
-            |k| captures.get_mut(k).unwrap().rotate_left(1)
-        );
+        rotate.difference(clear).for_each(|k| {
+            let queue = captures.get_mut(k).unwrap();
+            if reversed.contains(k) { queue.rotate_right(1) } else { queue.rotate_left(1) }
+        });
         // kill the captures that were sentenced to death
         clear.iter().for_each(|k| { captures.remove(k); });
         // clear everything like nothing happened
         self.to_pop.clear();
         self.to_clear.clear();
         self.to_rotate.clear();
+        self.reversed.clear();
     }
-    /// try parse token as capture
-    pub fn try_parse(&mut self, token: &str) -> Result<Option<Cap>, String> {
+    /// try parse token as capture, the note parser transforms the chords from captures
+    pub fn try_parse(&mut self, token: &str, note: &mut NoteParser) -> Result<Option<Cap>, String> {
         match token.as_bytes()[0] {
-            b if should_be_cap(b) => Ok(Some(self.process(token, b)?)),
+            b if should_be_cap(b) => Ok(Some(self.process(token, b, note)?)),
             _ => Ok(None),
         }
     }
     /// process the token as capture and return what operation was done
-    fn process(&mut self, token: &str, prefix: u8) -> Result<Cap, String> {
-        let key = token.chars().filter(|ch| ch.is_alphabetic()).collect();
+    fn process(&mut self, token: &str, prefix: u8, note: &mut NoteParser) -> Result<Cap, String> {
+        // prefix, key, closing, then transforms
+        let body = &token[1..];
+        let (key, rest) = body.split_at(body.find(|ch: char| !ch.is_alphabetic()).unwrap_or(body.len()));
+        let suffix = rest.strip_prefix(closing(prefix)).filter(|_| !key.is_empty()).ok_or_else(
+            || format!("invalid token as capture: {}", token)
+        )?;
+        let key = key.to_string();
         // use the key from captures if possible (avoid dup memory)
         let key = match self.captures.get_key_value(&key) {
             Some((k, _)) => Rc::clone(k),
             None => Rc::new(key),
         };
         match prefix {
-            CAP => Ok(Cap::Cap(key)),
-            POP | FRONT | CLEAR | ROTATE => Ok(self.process_front(
-                key, prefix,
-                parse_scale(token.as_bytes()),
-            )?),
+            CAP if suffix.is_empty() => Ok(Cap::Cap(key)),
+            CAP => Err(format!("chords can only be transformed when taken from captures: {}", token)),
+            POP | FRONT | CLEAR | ROTATE => {
+                let (transforms, retrograde) = parse_transforms(suffix)?;
+                Ok(self.process_front(key, prefix, &transforms, retrograde, note)?)
+            }
             _ => Err(format!("unknown capture instruction: {}", token)),
         }
     }
     /// process the token as pop/front/shift/clear
-    fn process_front(&mut self, key: Rc<String>, prefix: u8, scale: Option<f64>) -> Result<Cap, String> {
+    fn process_front(
+        &mut self, key: Rc<String>, prefix: u8, transforms: &[Transform], retrograde: bool, note: &mut NoteParser,
+    ) -> Result<Cap, String> {
         // update schedule
         let to = match prefix {
             CAP => Some(&mut self.to_cap),
@@ -125,13 +208,28 @@ impl CaptureParser {
         if let Some(to) = to {
             to.insert(Rc::clone(&key));
         }
-        // get front chord
-        let front = Rc::clone(self.captures.get(&key).ok_or_else(
+        if retrograde {
+            self.reversed.insert(Rc::clone(&key));
+        }
+        // get front chord (back if retrograde)
+        let queue = self.captures.get(&key).ok_or_else(
             || format!("key {} not found while trying to access front", &key)
-        )?.front().ok_or_else(
+        )?;
+        let (front, transpose) = if retrograde { queue.back() } else { queue.front() }.ok_or_else(
             || format!("captures with key {} is empty while trying to access front", &key)
-        )?);
-        // if scale, make new rc
-        Ok(Cap::Front(if let Some(r) = scale { Rc::new(front.scale(r)) } else { front }))
+        )?;
+        let front = Rc::clone(front);
+        // follow the transposition changed since the chord was captured, before the other transforms
+        let transpose = note.transpose() - transpose;
+        let transposed = (transpose != 0).then_some(Transform::Shift(transpose));
+        // if transformed, make new rc
+        if transposed.is_none() && transforms.is_empty() {
+            Ok(Cap::Front(front))
+        } else {
+            let chord = transposed.iter().chain(transforms).try_fold(
+                (*front).clone(), |chord, &t| transform(&chord, t, note)
+            )?;
+            Ok(Cap::Front(Rc::new(chord)))
+        }
     }
 }
@@ -0,0 +1,285 @@
+use std::rc::Rc;
+use std::str::SplitAsciiWhitespace;
+
+use crate::parsers::note::{parse_key, parse_tone};
+use crate::stores::note::Chord;
+use crate::stores::timbre::{Envelope, Oscillator, Timbre};
+use crate::stores::tuning::System;
+
+const PAN: &str = "pan";
+const INSTRUMENT: &str = "instrument";
+const ENVELOPE: &str = "envelope";
+const FERMATA: &str = "fermata";
+const TRILL: &str = "trill";
+const GRACE: &str = "grace";
+const PORTAMENTO: &str = "portamento";
+const VIBRATO: &str = "vibrato";
+const TREMOLO: &str = "tremolo";
+const TUNING: &str = "tuning";
+const KEY: &str = "key";
+const TRANSPOSE: &str = "transpose";
+/// semitones of each interval name
+const INTERVALS: [(&str, i32); 14] = [
+    ("P1", 0),
+    ("m2", 1),
+    ("M2", 2),
+    ("m3", 3),
+    ("M3", 4),
+    ("P4", 5),
+    ("A4", 6),
+    ("d5", 6),
+    ("P5", 7),
+    ("m6", 8),
+    ("M6", 9),
+    ("m7", 10),
+    ("M7", 11),
+    ("P8", 12),
+];
+
+#[derive(PartialEq, Debug)]
+pub enum Directive {
+    /// stereo position of each chord in a line
+    Pan(Vec<f64>),
+    /// shape of the wave
+    Instrument(Oscillator),
+    /// amplitude of each note over time
+    Envelope(Envelope),
+    /// how many times longer a note with fermata is held
+    Fermata(f64),
+    /// number of ornament notes per second
+    Trill(f64),
+    /// number of beats of acciaccatura
+    Grace(f64),
+    /// seconds to glide from the previous note
+    Portamento(f64),
+    /// rate in Hz and depth in cents of the pitch modulation
+    Vibrato(Option<(f64, f64)>),
+    /// rate in Hz and depth of the amplitude modulation
+    Tremolo(Option<(f64, f64)>),
+    /// Hz of the reference key
+    Reference(f64),
+    /// tuning system and its own Hz of the reference key (if any)
+    Tuning(System, Option<f64>),
+    /// paths of a scala scale and its optional keyboard mapping, read when applied
+    Scala(String, Option<String>),
+    /// key signature as number of sharps (positive) or flats (negative)
+    Key(i32),
+    /// semitones added to every pitch
+    Transpose(i32),
+}
+
+/// check if a line should be parsed as directive based on the first token
+/// (so that a mistake in the arguments is an error, not a comment)
+pub fn should_be_directive(line: &str) -> bool {
+    matches!(
+        line.split_ascii_whitespace().next(),
+        Some(PAN | INSTRUMENT | ENVELOPE | FERMATA | TRILL | GRACE | PORTAMENTO | VIBRATO | TREMOLO | TUNING | KEY | TRANSPOSE)
+    )
+}
+
+/// parse every remaining token as number
+fn parse_numbers(tokens: SplitAsciiWhitespace) -> Result<Vec<f64>, String> {
+    tokens.map(|token| token.parse::<f64>().map_err(
+        |_| format!("invalid token as number: {}", token)
+    )).collect()
+}
+
+/// parse the name of the oscillator and its arguments
+fn parse_oscillator(mut tokens: SplitAsciiWhitespace) -> Result<Oscillator, String> {
+    let name = tokens.next().ok_or("missing instrument name")?;
+    let args = parse_numbers(tokens)?;
+    match (name, args.as_slice()) {
+        ("sine", []) => Ok(Oscillator::Sine),
+        ("square", []) => Ok(Oscillator::Square),
+        ("saw", []) => Ok(Oscillator::Saw),
+        ("triangle", []) => Ok(Oscillator::Triangle),
+        ("noise", []) => Ok(Oscillator::Noise),
+        ("pulse", &[width]) if 0.0 < width && width < 1.0 => Ok(Oscillator::Pulse(width)),
+        ("pulse", _) => Err("pulse expects one width between 0 and 1".to_string()),
+        ("harmonics", amps) if !amps.is_empty() => Ok(Oscillator::Harmonics(amps.to_vec())),
+        ("harmonics", _) => Err("harmonics expects at least one amplitude".to_string()),
+        _ => Err(format!("invalid instrument: {} {:?}", name, args)),
+    }
+}
+
+/// parse token as number of semitones (e.g. `+3`, `-12`) or interval name (e.g. `+M3`, `-P5`)
+pub fn parse_interval(token: &str) -> Result<i32, String> {
+    let (sign, interval) = match token.strip_prefix('-') {
+        Some(interval) => (-1, interval),
+        None => (1, token.strip_prefix('+').unwrap_or(token)),
+    };
+    match INTERVALS.iter().find(|&&(name, _)| name == interval) {
+        Some(&(_, semitones)) => Ok(sign * semitones),
+        None => interval.parse::<i32>().map(|semitones| sign * semitones).map_err(
+            |_| format!("invalid token as interval: {}", token)
+        ),
+    }
+}
+
+/// parse the reference frequency, or the name of the tuning system and its arguments
+fn parse_tuning(mut tokens: SplitAsciiWhitespace) -> Result<Directive, String> {
+    let name = tokens.next().ok_or("missing tuning system or reference frequency")?;
+    if let Ok(reference) = name.parse::<f64>() {
+        return match tokens.next() {
+            None if reference > 0.0 => Ok(Directive::Reference(reference)),
+            _ => Err("tuning expects one reference frequency (> 0)".to_string()),
+        };
+    }
+    let args: Vec<&str> = tokens.collect();
+    // tonic of the tonic-relative systems, C by default
+    let tonic = || match args.as_slice() {
+        [] => Ok(0),
+        [tonic] => parse_tone(tonic).ok_or_else(|| format!("invalid token as tonic: {}", tonic)),
+        _ => Err(format!("{} expects at most one tonic", name)),
+    };
+    match (name, args.as_slice()) {
+        ("equal", []) => Ok(Directive::Tuning(System::Equal, None)),
+        ("just", _) => Ok(Directive::Tuning(System::Just(tonic()?), None)),
+        ("pythagorean", _) => Ok(Directive::Tuning(System::Pythagorean(tonic()?), None)),
+        ("meantone", _) => Ok(Directive::Tuning(System::Meantone(tonic()?), None)),
+        ("scala", &[scl]) => Ok(Directive::Scala(scl.to_string(), None)),
+        ("scala", &[scl, kbm]) => Ok(Directive::Scala(scl.to_string(), Some(kbm.to_string()))),
+        ("scala", _) => Err("scala expects a .scl file and an optional .kbm file".to_string()),
+        _ => Err(format!("invalid tuning: {} {:?}", name, args)),
+    }
+}
+
+/// parse the name of the envelope and its arguments
+fn parse_envelope(mut tokens: SplitAsciiWhitespace) -> Result<Envelope, String> {
+    let name = tokens.next().ok_or("missing envelope name")?;
+    let args = parse_numbers(tokens)?;
+    match (name, args.as_slice()) {
+        ("sinusoid", []) => Ok(Envelope::Sinusoid),
+        ("adsr", &[attack, decay, sustain, release])
+        if attack >= 0.0 && decay >= 0.0 && (0.0..=1.0).contains(&sustain) && release >= 0.0 => {
+            Ok(Envelope::Adsr { attack, decay, sustain, release })
+        }
+        ("adsr", _) => Err("adsr expects attack, decay, release (>= 0) and sustain (0 to 1)".to_string()),
+        _ => Err(format!("invalid envelope: {} {:?}", name, args)),
+    }
+}
+
+/// parse a line as directive
+fn parse_directive(mut tokens: SplitAsciiWhitespace) -> Result<Directive, String> {
+    match tokens.next() {
+        Some(PAN) => {
+            let pans = parse_numbers(tokens)?;
+            match pans.iter().find(|p| !(-1.0..=1.0).contains(*p)) {
+                Some(p) => Err(format!("pan is out of range [-1, 1]: {}", p)),
+                None => Ok(Directive::Pan(pans)),
+            }
+        }
+        Some(INSTRUMENT) => Ok(Directive::Instrument(parse_oscillator(tokens)?)),
+        Some(ENVELOPE) => Ok(Directive::Envelope(parse_envelope(tokens)?)),
+        Some(FERMATA) => match parse_numbers(tokens)?.as_slice() {
+            &[factor] if factor >= 1.0 => Ok(Directive::Fermata(factor)),
+            _ => Err("fermata expects one factor (>= 1)".to_string()),
+        }
+        Some(TRILL) => match parse_numbers(tokens)?.as_slice() {
+            &[speed] if speed > 0.0 => Ok(Directive::Trill(speed)),
+            _ => Err("trill expects one number of notes per second (> 0)".to_string()),
+        }
+        Some(GRACE) => match parse_numbers(tokens)?.as_slice() {
+            &[value] if value > 0.0 => Ok(Directive::Grace(1.0 / value)),
+            _ => Err("grace expects one note value (> 0)".to_string()),
+        }
+        Some(PORTAMENTO) => match parse_numbers(tokens)?.as_slice() {
+            &[seconds] if seconds >= 0.0 => Ok(Directive::Portamento(seconds)),
+            _ => Err("portamento expects one number of seconds (>= 0)".to_string()),
+        }
+        Some(VIBRATO) => match parse_numbers(tokens)?.as_slice() {
+            [] => Ok(Directive::Vibrato(None)),
+            &[rate, cents] if rate > 0.0 && cents >= 0.0 => Ok(Directive::Vibrato(Some((rate, cents)))),
+            _ => Err("vibrato expects nothing, or a rate (> 0) and a depth in cents (>= 0)".to_string()),
+        }
+        Some(TREMOLO) => match parse_numbers(tokens)?.as_slice() {
+            [] => Ok(Directive::Tremolo(None)),
+            &[rate, depth] if rate > 0.0 && (0.0..=1.0).contains(&depth) => Ok(Directive::Tremolo(Some((rate, depth)))),
+            _ => Err("tremolo expects nothing, or a rate (> 0) and a depth (0 to 1)".to_string()),
+        }
+        Some(TUNING) => parse_tuning(tokens),
+        Some(TRANSPOSE) => match (tokens.next(), tokens.next()) {
+            (Some(interval), None) => Ok(Directive::Transpose(parse_interval(interval)?)),
+            _ => Err("transpose expects one interval, e.g. +2, -P5".to_string()),
+        }
+        Some(KEY) => match (tokens.next(), tokens.next()) {
+            (Some(key), None) => parse_key(key).map(Directive::Key).ok_or_else(|| format!("invalid token as key: {}", key)),
+            _ => Err("key expects one key, e.g. Eb or f#m".to_string()),
+        }
+        token => Err(format!("invalid token as directive: {:?}", token)),
+    }
+}
+
+pub struct DirectiveParser {
+    /// stereo position of each chord in a line, the last one is used for the rest
+    pans: Vec<f64>,
+    /// timbre of the following chords
+    timbre: Rc<Timbre>,
+    /// how many times longer a note with fermata is held
+    fermata: f64,
+    /// number of beats of acciaccatura
+    grace: f64,
+}
+
+impl DirectiveParser {
+    pub fn new() -> Self {
+        Self { pans: Vec::new(), timbre: Rc::default(), fermata: 2.0, grace: 1.0 / 32.0 }
+    }
+    /// parse a line as directive
+    pub fn parse(&self, tokens: SplitAsciiWhitespace) -> Result<Directive, String> {
+        parse_directive(tokens)
+    }
+    /// update the state of following chords
+    /// returns the directive back if it is meant for another parser
+    pub fn apply(&mut self, directive: Directive) -> Option<Directive> {
+        match directive {
+            Directive::Pan(pans) => self.pans = pans,
+            Directive::Instrument(oscillator) => self.timbre = Rc::new(Timbre {
+                oscillator,
+                ..(*self.timbre).clone()
+            }),
+            Directive::Envelope(envelope) => self.timbre = Rc::new(Timbre {
+                envelope,
+                ..(*self.timbre).clone()
+            }),
+            Directive::Portamento(portamento) => self.timbre = Rc::new(Timbre {
+                portamento,
+                ..(*self.timbre).clone()
+            }),
+            Directive::Vibrato(vibrato) => self.timbre = Rc::new(Timbre {
+                vibrato,
+                ..(*self.timbre).clone()
+            }),
+            Directive::Tremolo(tremolo) => self.timbre = Rc::new(Timbre {
+                tremolo,
+                ..(*self.timbre).clone()
+            }),
+            Directive::Fermata(factor) => self.fermata = factor,
+            Directive::Grace(beat) => self.grace = beat,
+            directive => return Some(directive),
+        }
+        None
+    }
+    /// how many times longer a note with fermata is held
+    pub fn fermata(&self) -> f64 {
+        self.fermata
+    }
+    /// number of beats of acciaccatura
+    pub fn grace(&self) -> f64 {
+        self.grace
+    }
+    /// stereo position of the i-th chord in a line
+    fn pan(&self, i: usize) -> f64 {
+        self.pans.get(i).or_else(|| self.pans.last()).copied().unwrap_or(0.0)
+    }
+    /// apply the current state to the i-th chord in a line
+    /// (only make new rc if something changes)
+    pub fn decorate(&self, chord: Rc<Chord>, i: usize) -> Rc<Chord> {
+        let pan = self.pan(i);
+        if chord.pan == pan && chord.timbre == self.timbre {
+            chord
+        } else {
+            Rc::new(Chord { pan, timbre: Rc::clone(&self.timbre), ..(*chord).clone() })
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::stores::note::Line;
+
+const CRESCENDO: &str = "<";
+const DIMINUENDO: &str = ">";
+const SFORZANDO: &str = "sfz";
+/// level of each dynamic marking, from soft to loud
+const LEVELS: [(&str, f64); 8] = [
+    ("ppp", 0.1),
+    ("pp", 0.2),
+    ("p", 0.35),
+    ("mp", 0.5),
+    ("mf", 0.65),
+    ("f", 0.8),
+    ("ff", 0.9),
+    ("fff", 1.0),
+];
+/// level of a line without any dynamic marking (f), so that ff, fff and sfz are louder
+const DEFAULT: f64 = 0.8;
+
+#[derive(PartialEq, Debug)]
+pub enum Dyn {
+    /// e.g. mf
+    Level(f64),
+    /// sfz, only for the next line
+    Sforzando,
+    /// <
+    Crescendo,
+    /// >
+    Diminuendo,
+}
+
+/// check if a line should be parsed as dynamic, i.e. the line is exactly one dynamic token
+/// (otherwise it's a comment, e.g. `f is loud`)
+pub fn should_be_dyn(line: &str) -> bool {
+    let mut tokens = line.split_ascii_whitespace();
+    match (tokens.next(), tokens.next()) {
+        (Some(token), None) => matches!(token, CRESCENDO | DIMINUENDO | SFORZANDO) || LEVELS.iter().any(|&(d, _)| d == token),
+        _ => false,
+    }
+}
+
+pub struct DynamicParser {
+    /// current level
+    level: f64,
+    /// Crescendo or Diminuendo if there is a hairpin waiting for its target level
+    hairpin: Option<Dyn>,
+    /// lines inside the hairpin, can only be written when the target level is known
+    pending: Vec<Line>,
+    /// should the next line be sforzando
+    sforzando: bool,
+}
+
+impl DynamicParser {
+    pub fn new() -> Self {
+        Self { level: DEFAULT, hairpin: None, pending: Vec::new(), sforzando: false }
+    }
+    /// parse token as dynamic
+    pub fn parse(&self, token: &str) -> Result<Dyn, String> {
+        match token {
+            CRESCENDO => Ok(Dyn::Crescendo),
+            DIMINUENDO => Ok(Dyn::Diminuendo),
+            SFORZANDO => Ok(Dyn::Sforzando),
+            _ => match LEVELS.iter().find(|&&(d, _)| d == token) {
+                Some(&(_, level)) => Ok(Dyn::Level(level)),
+                None => Err(format!("invalid token as dynamic: {}", token)),
+            }
+        }
+    }
+    /// return if a hairpin is waiting for its target level
+    pub fn on_hairpin(&self) -> bool {
+        self.hairpin.is_some()
+    }
+    /// update the level, return the lines of the hairpin if it ends
+    pub fn update(&mut self, dynamic: Dyn) -> Result<Vec<Line>, String> {
+        match (dynamic, &self.hairpin) {
+            (Dyn::Crescendo | Dyn::Diminuendo, Some(_)) => Err("hairpin inside another hairpin".to_string()),
+            (Dyn::Sforzando, Some(_)) => Err("sforzando inside hairpin".to_string()),
+            (hairpin @ (Dyn::Crescendo | Dyn::Diminuendo), None) => {
+                self.hairpin = Some(hairpin);
+                Ok(Vec::new())
+            }
+            (Dyn::Sforzando, None) => {
+                self.sforzando = true;
+                Ok(Vec::new())
+            }
+            (Dyn::Level(level), None) => {
+                self.level = level;
+                Ok(Vec::new())
+            }
+            (Dyn::Level(level), Some(hairpin)) => {
+                match hairpin {
+                    Dyn::Crescendo if level <= self.level => return Err("crescendo to a softer dynamic".to_string()),
+                    Dyn::Diminuendo if level >= self.level => return Err("diminuendo to a louder dynamic".to_string()),
+                    _ => (),
+                }
+                Ok(self.ramp(level))
+            }
+        }
+    }
+    /// set the gain of a new line, and keep it if it is inside a hairpin
+    /// return the line if it can be written now
+    pub fn push(&mut self, mut line: Line) -> Option<Line> {
+        if self.on_hairpin() {
+            self.pending.push(line);
+            None
+        } else {
+            let level = if self.sforzando { LEVELS[LEVELS.len() - 1].1 } else { self.level };
+            self.sforzando = false;
+            line.set_gain(level, level);
+            Some(line)
+        }
+    }
+    /// make sure there's no hairpin waiting
+    pub fn finish(&self) -> Result<(), String> {
+        if self.on_hairpin() {
+            Err("hairpin does not end with a dynamic".to_string())
+        } else {
+            Ok(())
+        }
+    }
+    /// ramp the level of the pending lines to the target level
+    fn ramp(&mut self, target: f64) -> Vec<Line> {
+        let mut lines = std::mem::take(&mut self.pending);
+        let total = lines.iter().map(|line| line.offset()).sum::<usize>() as f64;
+        let level = |t: usize| self.level + (target - self.level) * t as f64 / total;
+        let mut t = 0;
+        for line in lines.iter_mut() {
+            let offset = line.offset();
+            line.set_gain(level(t), level(t + offset));
+            t += offset;
+        }
+        self.level = target;
+        self.hairpin = None;
+        lines
+    }
+}
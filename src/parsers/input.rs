@@ -1,16 +1,19 @@
 use std::fmt::{Display, Formatter};
 use std::fs::File;
 use std::io::Error;
-use std::iter::Peekable;
 use std::rc::Rc;
-use std::str::SplitAsciiWhitespace;
 
 use crate::parsers::capture::{Cap, CaptureParser, should_be_cap};
+use crate::parsers::directive::{Directive, DirectiveParser, should_be_directive};
+use crate::parsers::dynamic::{DynamicParser, should_be_dyn};
+use crate::parsers::macros::{Macro, MacroParser, should_be_macro};
+use crate::parsers::navigation::{NavigationParser, should_be_nav};
 use crate::parsers::note::{Note, NoteParser};
 use crate::parsers::repeat::{Rep, RepeatParser, should_be_rep};
-use crate::stores::note::{Chord, Line};
-use crate::stores::waveform::Waveform;
-use crate::writer::Writer;
+use crate::parsers::scala;
+use crate::stores::note::{Articulation, Chord, Grace, Line, Pitch, Slur};
+use crate::stores::waveform::{CHANNELS, Waveform};
+use crate::writer::{Format, Writer};
 
 /// Length, Frequency, Capture, Front, None
 #[derive(PartialEq)]
@@ -24,7 +27,7 @@ impl Display for Token {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", match &self {
             Self::Note(Note::Pitch(_)) => "note frequency",
-            Self::Note(Note::Len(_, _)) => "note length",
+            Self::Note(Note::Len(..)) => "note length",
             Self::Cap(Cap::Cap(_)) => "cap capture",
             Self::Cap(Cap::Front(_)) => "cap front",
             Self::None => "EOL",
@@ -49,94 +52,285 @@ pub struct InputParser {
     cap: CaptureParser,
     rep: RepeatParser,
     note: NoteParser,
+    dir: DirectiveParser,
+    dy: DynamicParser,
+    mac: MacroParser,
+    nav: NavigationParser,
     wave: Waveform,
+    /// the last line of chords and its line number, held back in case the next line has acciaccaturas
+    held: Option<(Line, usize)>,
+    /// is each chord position in the middle of a slur
+    slurs: Vec<bool>,
+    /// number of the input line being parsed, 0 at the end of input
+    line: usize,
 }
 
 impl InputParser {
-    pub fn new(output: File, max: usize, fps: u32) -> Self {
+    pub fn new(output: File, format: Format, max: usize, fps: u32) -> Self {
         Self {
-            wr: Writer::new(output),
+            wr: Writer::new(output, format),
             cap: CaptureParser::new(),
             rep: RepeatParser::new(),
             note: NoteParser::new(),
+            dir: DirectiveParser::new(),
+            dy: DynamicParser::new(),
+            mac: MacroParser::new(),
+            nav: NavigationParser::new(),
             wave: Waveform::new(max, fps),
+            held: None,
+            slurs: Vec::new(),
+            line: 0,
         }
     }
     /// parse all lines as input and write output to file
     pub fn write<I: Iterator<Item=String>>(&mut self, lines: I) -> Result<(), Error> {
-        self.wr.start(self.wave.fps)?;
+        self.wr.start(self.wave.fps, CHANNELS)?;
         // not using for loops here because CLion won't give me autocomplete
         // lines.for_each(|line| self.parse_line(line.trim()));
-        lines.enumerate().for_each(
-            |(i, line)| self.parse_line(line.trim()).unwrap_or_else(
+        lines.enumerate().for_each(|(i, line)| {
+            self.line = i + 1;
+            self.parse_line(line.trim()).unwrap_or_else(
                 |why| panic!("on line {}, {}", i + 1, why)
             )
+        });
+        self.finish().unwrap_or_else(
+            |why| panic!("at the end of input, {}", why)
         );
-        Ok(self.wr.finish()?)
+        self.wr.write(self.wave.flush())?;
+        self.wr.finish()
+    }
+    /// release what's left at the end of input, and make sure nothing waits for its end
+    fn finish(&mut self) -> Result<(), String> {
+        // no line is being parsed anymore, errors of the held line name its own line
+        self.line = 0;
+        self.mac.finish()?;
+        self.release_held()?;
+        self.dy.finish()?;
+        self.finish_slurs()?;
+        self.repeat()?;
+        self.rep.finish()?;
+        self.nav.finish()
     }
     /// parse a line from input
     fn parse_line(&mut self, line: &str) -> Result<(), String> {
-        Ok(match line.parse() {
+        // the lines of a macro are only parsed when it's called
+        if self.mac.on_rec() {
+            return self.mac.record(line);
+        }
+        match line.parse() {
             // line containing single usize
             Ok(bpm) => self.wave.bpm = bpm,
             Err(..) => {
                 let mut tokens = line.split_ascii_whitespace().peekable();
                 match tokens.peek() {
-                    Some(&token) if should_be_rep(token) => self.parse_repeat(tokens)?,
-                    Some(&token) if should_be_chords(token) => self.parse_chords(tokens)?,
+                    Some(&token) if should_be_rep(token) => self.parse_bar(line)?,
+                    Some(_) if should_be_dyn(line) => self.parse_dyn(line)?,
+                    Some(_) if should_be_directive(line) => self.parse_directive(line)?,
+                    Some(_) if should_be_macro(line) => self.parse_macro(line)?,
+                    Some(_) if should_be_nav(line) => self.parse_nav(line)?,
+                    Some(&token) if should_be_chords(token) => self.parse_bar(line)?,
+                    // an empty line is the end of a bar
+                    None => self.note.end_bar(),
                     _ => { /* token is comment */ }
                 }
             }
-        })
+        }
+        Ok(())
     }
-    /// write a line to file
+    /// hold back a new line, and release the last one
+    fn hold(&mut self, line: Line) -> Result<(), String> {
+        match self.held.replace((line, self.line)) {
+            Some((held, number)) => self.release(held, number),
+            None => Ok(()),
+        }
+    }
+    /// release the held line (if any), nothing can borrow from it anymore
+    fn release_held(&mut self) -> Result<(), String> {
+        match self.held.take() {
+            Some((held, number)) => self.release(held, number),
+            None => Ok(()),
+        }
+    }
+    /// apply dynamic to the line written on line `number` and emit it (unless it's inside a hairpin)
+    fn release(&mut self, line: Line, number: usize) -> Result<(), String> {
+        let result = match self.dy.push(line) {
+            Some(line) => self.emit_line(line),
+            None => Ok(()),
+        };
+        // the line is released while parsing a later line (or at the end of input), so name its own
+        result.map_err(|why| if number == self.line { why } else { format!("in the chords on line {}, {}", number, why) })
+    }
+    /// record the line if repeat is on, otherwise write it to file
+    fn emit_line(&mut self, line: Line) -> Result<(), String> {
+        self.repeat()?;
+        if self.rep.on_rec() {
+            self.rep.push(line)
+        } else {
+            self.write_line(&line)
+        }
+    }
+    /// make sure there's no slur waiting for its end
+    fn finish_slurs(&self) -> Result<(), String> {
+        match self.slurs.iter().position(|&slurring| slurring) {
+            Some(voice) => Err(format!("slur of chord {} does not end", voice + 1)),
+            None => Ok(()),
+        }
+    }
+    /// record a line played for the first time, then write it to file
     fn write_line(&mut self, line: &Line) -> Result<(), String> {
+        self.nav.push(line)?;
+        self.play_line(line)
+    }
+    /// write a line to file
+    fn play_line(&mut self, line: &Line) -> Result<(), String> {
         self.wave.fold_with_line(line)?;
-        Ok(self.wr.write(self.wave.drain(line.offset())).unwrap())
+        self.wr.write(self.wave.drain(line.offset())).map_err(|why| why.to_string())
     }
 }
 
-/// parse repeat
+/// parse directive
 impl InputParser {
-    /// parse a line of input as repeat
-    fn parse_repeat(&mut self, mut tokens: Peekable<SplitAsciiWhitespace>) -> Result<(), String> {
-        // current token type
-        let mut cty = Some(self.rep.parse(tokens.next().unwrap())?);
-        while let Some(ty) = cty {
-            // next token type
-            let nty = tokens.next().and_then(|token| Some(self.rep.parse(token).ok()?));
-
-            match &ty {
-                Rep::RepeatStart => self.rep.start(&[0]),
-                Rep::VoltaStart(vs) => self.rep.start(&vs),
-                Rep::RepeatEnd | Rep::VoltaEnd => self.rep.start(&[!0]),
+    /// parse a line of input as directive
+    fn parse_directive(&mut self, line: &str) -> Result<(), String> {
+        let directive = self.dir.parse(line.split_ascii_whitespace())?;
+        // directives that are not about the state of chords
+        match self.dir.apply(directive) {
+            Some(Directive::Trill(speed)) => self.note.set_speed(speed),
+            Some(Directive::Reference(reference)) => self.note.set_reference(reference),
+            Some(Directive::Tuning(system, reference)) => self.note.set_tuning(system, reference),
+            Some(Directive::Scala(scl, kbm)) => {
+                let (system, reference) = scala::load(&scl, kbm.as_deref())?;
+                self.note.set_tuning(system, reference)
             }
-            match (ty, &nty) {
-                // change repeat trigger to VoltaEnd
-                (Rep::RepeatEnd, Some(Rep::VoltaStart(_))) => self.rep.set_trigger(Rep::VoltaEnd)?,
-                // if current is the repeat trigger
-                (end, Some(Rep::RepeatStart) | None) if self.rep.get_trigger() == end => {
-                    // move self.rep to rep
-                    let rep = std::mem::take(&mut self.rep);
-                    // now there's no borrowing 2 values from self at tho same time
-                    rep.repeat(|line| Ok(self.write_line(line)?))?;
-                    // move back
-                    self.rep = rep;
-                    // reset repeat
-                    self.rep.clear();
+            Some(Directive::Key(fifths)) => self.note.set_key(fifths),
+            Some(Directive::Transpose(semitones)) => self.note.set_transpose(semitones),
+            Some(directive) => return Err(format!("unhandled directive: {:?}", directive)),
+            None => (),
+        }
+        Ok(())
+    }
+}
+
+/// parse macro
+impl InputParser {
+    /// parse a line of input as macro
+    fn parse_macro(&mut self, line: &str) -> Result<(), String> {
+        match self.mac.parse(line.split_ascii_whitespace())? {
+            Macro::Define(name) => self.mac.define(name),
+            Macro::End => Err("end of macro without define".to_string()),
+            Macro::Call(name, transpose, bpm) => {
+                let lines = self.mac.enter(&name)?;
+                // transposition and BPM are only changed during the call
+                let (outer, bpm) = (self.note.transpose(), bpm.map(|bpm| std::mem::replace(&mut self.wave.bpm, bpm)));
+                self.note.set_transpose(outer + transpose);
+                let result = lines.iter().enumerate().try_for_each(
+                    |(i, line)| self.parse_line(line).map_err(|why| format!("in macro {} on line {}, {}", name, i + 1, why))
+                );
+                self.note.set_transpose(outer);
+                if let Some(bpm) = bpm {
+                    self.wave.bpm = bpm;
                 }
-                _ => ()
+                self.mac.leave();
+                result
             }
-            cty = nty;
+        }
+    }
+}
+
+/// parse navigation
+impl InputParser {
+    /// parse a line of input as navigation
+    fn parse_nav(&mut self, line: &str) -> Result<(), String> {
+        if self.dy.on_hairpin() {
+            return Err("navigation inside hairpin".to_string());
+        }
+        self.release_held()?;
+        self.repeat()?;
+        if self.rep.on_rec() {
+            return Err("navigation inside repeat".to_string());
+        }
+        self.note.end_bar();
+        let nav = self.nav.parse(line.split_ascii_whitespace())?;
+        for line in self.nav.apply(nav)? {
+            self.play_line(&line)?;
         }
         Ok(())
     }
 }
 
+/// parse dynamic
+impl InputParser {
+    /// parse a line of input as dynamic
+    fn parse_dyn(&mut self, line: &str) -> Result<(), String> {
+        self.release_held()?;
+        let dynamic = self.dy.parse(line)?;
+        // write the lines of the hairpin if it ends
+        for line in self.dy.update(dynamic)? {
+            self.emit_line(line)?;
+        }
+        Ok(())
+    }
+}
+
+/// parse repeat
+impl InputParser {
+    /// parse a line of input as repeat tokens, chords (if any), then repeat tokens again
+    fn parse_bar(&mut self, line: &str) -> Result<(), String> {
+        let tokens = line.split_ascii_whitespace().collect::<Vec<&str>>();
+        // the chords are between the repeat tokens at both sides
+        let start = tokens.iter().position(|token| !should_be_rep(token)).unwrap_or(tokens.len());
+        let end = tokens.iter().rposition(|token| !should_be_rep(token)).map_or(start, |i| i + 1);
+        for token in &tokens[..start] {
+            self.parse_repeat(token)?;
+        }
+        if start < end {
+            if !should_be_chords(tokens[start]) {
+                return Err(format!("invalid token as chords: {}", tokens[start]));
+            }
+            if let Some(token) = tokens[start..end].iter().find(|token| should_be_rep(token)) {
+                return Err(format!("repeat token between chords: {}", token));
+            }
+            self.parse_chords(tokens[start..end].iter().copied())?;
+        }
+        for token in &tokens[end..] {
+            self.parse_repeat(token)?;
+        }
+        Ok(())
+    }
+    /// parse a repeat token
+    fn parse_repeat(&mut self, token: &str) -> Result<(), String> {
+        if self.dy.on_hairpin() {
+            return Err("repeat inside hairpin".to_string());
+        }
+        self.release_held()?;
+        self.note.end_bar();
+        let rep = self.rep.parse(token)?;
+        // the section is repeated as soon as it's clear that no voltas follow
+        if !matches!(rep, Rep::VoltaStart(_)) {
+            self.repeat()?;
+        }
+        match rep {
+            Rep::RepeatStart => self.rep.open(),
+            Rep::VoltaStart(vs) => self.rep.start(&vs),
+            Rep::RepeatEnd(times) => self.rep.end(times, self.line),
+            Rep::VoltaEnd => self.rep.end_volta(self.line),
+        }
+    }
+    /// repeat the innermost section if it has ended
+    fn repeat(&mut self) -> Result<(), String> {
+        if !self.rep.ended() {
+            return Ok(());
+        }
+        // the section is moved out, so the outer section (if any) can record what it plays
+        let section = self.rep.close()?;
+        section.repeat(|line| self.emit_line(line.clone()))
+    }
+}
+
 /// parse chords
 impl InputParser {
     /// parse a line of input as chords (and captures)
-    fn parse_chords(&mut self, mut tokens: Peekable<SplitAsciiWhitespace>) -> Result<(), String> {
+    fn parse_chords<'a>(&mut self, mut tokens: impl Iterator<Item=&'a str>) -> Result<(), String> {
         let (mut chord, mut rc) = (Chord::new(), Rc::new(Chord::new()));
         let mut line = Line::new();
         // current token type
@@ -150,12 +344,13 @@ impl InputParser {
             };
             match &cty {
                 // update set of keys to capture
-                Token::Cap(Cap::Cap(key)) => self.cap.will_capture(Rc::clone(&key)),
+                Token::Cap(Cap::Cap(key)) => self.cap.will_capture(Rc::clone(key)),
                 // update current chord's length & size
-                Token::Note(Note::Len(length, staccato)) => {
-                    let length = self.wave.frame_count(*length);
-                    chord.length = length;
-                    chord.size = if *staccato { length * 2 } else { length };
+                Token::Note(Note::Len(length, size, articulation)) => {
+                    let fermata = if articulation.fermata { self.dir.fermata() } else { 1.0 };
+                    chord.length = self.wave.frame_count(*length * fermata);
+                    chord.size = self.wave.frame_count(*size * fermata);
+                    chord.articulation = *articulation;
                 }
                 // extend current chord from captures and update to_shift/to_clear
                 // Token::Cap(Cap::Front(captured)) => if chord.is_new() && rc.is_new() {
@@ -165,7 +360,7 @@ impl InputParser {
                     chord.extend(captured)
                 },
                 // push new frequency to current chord
-                Token::Note(Note::Pitch(frequency)) => chord.push(*frequency),
+                Token::Note(Note::Pitch(pitch)) => chord.push(*pitch),
                 _ => {}
             }
             // help how do I refactor this monstrosity
@@ -176,9 +371,9 @@ impl InputParser {
             //  F: Cap::Front
             match (&cty, &nty) {
                 // ignore (L, F|P)
-                (Token::Note(Note::Len(_, _)), Token::Cap(Cap::Front(_)) | Token::Note(Note::Pitch(_))) => (),
+                (Token::Note(Note::Len(..)), Token::Cap(Cap::Front(_)) | Token::Note(Note::Pitch(_))) => (),
                 // error (L, L|C|N) | (C, P|N)
-                (Token::Note(Note::Len(_, _)), _) | (Token::Cap(Cap::Cap(_)), Token::Note(Note::Pitch(_)) | Token::None) => {
+                (Token::Note(Note::Len(..)), _) | (Token::Cap(Cap::Cap(_)), Token::Note(Note::Pitch(_)) | Token::None) => {
                     return Err(format!("invalid token sequence: ({}, {})", cty, nty));
                 }
                 // ignore (P, P) | (F, P|F)
@@ -186,13 +381,24 @@ impl InputParser {
                 (Token::Cap(Cap::Front(_)), Token::Note(Note::Pitch(_)) | Token::Cap(Cap::Front(_))) => (),
                 // push to line and capture (P, L|C|F|N) | (F, L|C|N)
                 (Token::Note(Note::Pitch(_)) | Token::Cap(Cap::Front(_)), _) => {
-                    let new = if chord.is_empty() {
+                    // grace notes are neither part of the chord nor captured
+                    let graces = chord.take_graces();
+                    let new = self.dir.decorate(if chord.is_empty() {
                         rc
                     } else {
                         Rc::new(chord + (*rc).clone())
-                    };
-                    self.cap.capture(Rc::clone(&new));
-                    line.push(new);
+                    }, line.count());
+                    if new.is_empty() {
+                        return Err("grace notes without any principal note".to_string());
+                    }
+                    // captured as written, the middle of a slur depends on where it's played
+                    self.cap.capture(Rc::clone(&new), self.note.transpose());
+                    let new = self.slur(new, line.count())?;
+                    if graces.is_empty() {
+                        line.push(new);
+                    } else {
+                        self.push_graces(&mut line, new, graces)?;
+                    }
                     chord = Chord::new();
                     rc = Rc::new(Chord::new());
                 }
@@ -200,16 +406,70 @@ impl InputParser {
             }
             cty = nty
         }
-        if self.rep.on_rec() {
-            self.rep.push(line)?;
+        self.hold(line)?;
+        self.cap.update();
+        Ok(())
+    }
+    /// mark a chord as the start, middle or end of a slur in its position
+    fn slur(&mut self, chord: Rc<Chord>, voice: usize) -> Result<Rc<Chord>, String> {
+        if self.slurs.len() <= voice {
+            self.slurs.resize(voice + 1, false);
+        }
+        let slur = match (self.slurs[voice], chord.articulation.slur) {
+            (true, Some(Slur::Start)) => return Err(format!("slur inside another slur at chord {}", voice + 1)),
+            (false, Some(Slur::End)) => return Err(format!("slur ends without a start at chord {}", voice + 1)),
+            (true, None) => Some(Slur::Middle),
+            (_, slur) => slur,
+        };
+        self.slurs[voice] = matches!(slur, Some(Slur::Start | Slur::Middle));
+        Ok(if slur == chord.articulation.slur {
+            chord
         } else {
-            self.write_line(&line)?;
+            Rc::new(Chord { articulation: Articulation { slur, ..chord.articulation }, ..(*chord).clone() })
+        })
+    }
+    /// push principal chord to the line, and its grace notes before it
+    fn push_graces(&mut self, line: &mut Line, principal: Rc<Chord>, graces: Vec<Pitch>) -> Result<(), String> {
+        let voice = line.count();
+        let kind = graces[0].grace;
+        if graces.iter().any(|p| p.grace != kind) {
+            return Err("cannot mix appoggiatura and acciaccatura in a chord".to_string());
         }
-        Ok(self.cap.update())
+        let pitches = graces.iter().map(|&p| Pitch { grace: None, ..p }).collect();
+        // number of frames taken by the grace notes
+        let grace = match kind {
+            Some(Grace::Acciaccatura) => self.wave.frame_count(self.dir.grace()).min(principal.length / 2),
+            _ => principal.length / 2,
+        };
+        // the chord in the same position of the held line
+        let preceding = self.held.as_ref().and_then(|(held, _)| Some((held.offset(), Rc::clone(held.get(voice)?))));
+        match (kind, preceding) {
+            // take the end of the preceding chord
+            (Some(Grace::Acciaccatura), Some((offset, preceding))) if grace <= offset / 2 => {
+                let start = offset - grace;
+                let (held, _) = self.held.as_mut().unwrap();
+                held.replace(voice, Rc::new(Chord {
+                    length: preceding.length.min(start.saturating_sub(preceding.delay)),
+                    ..(*preceding).clone()
+                }));
+                held.push_grace(Rc::new(Chord { pitches, length: grace, size: offset, delay: start, ..(*principal).clone() }));
+                line.push(principal);
+            }
+            // take the start of the principal chord (also acciaccatura without preceding chord)
+            _ => {
+                line.push_grace(Rc::new(Chord { pitches, length: grace, ..(*principal).clone() }));
+                line.push(Rc::new(Chord {
+                    length: principal.length - grace,
+                    delay: principal.delay + grace,
+                    ..(*principal).clone()
+                }));
+            }
+        }
+        Ok(())
     }
     /// get specific type of chord token
     fn chord_type(&mut self, token: &str) -> Result<Token, String> {
-        if let Some(cap) = self.cap.try_parse(token)? {
+        if let Some(cap) = self.cap.try_parse(token, &mut self.note)? {
             Ok(Token::Cap(cap))
         } else if let Some(note) = self.note.try_parse(token)? {
             Ok(Token::Note(note))
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::str::SplitAsciiWhitespace;
+
+use crate::parsers::directive::parse_interval;
+
+const DEFINE: &str = "define";
+const END: &str = "end";
+const CALL: &str = "call";
+const TRANSPOSE: &str = "transpose";
+const BPM: &str = "bpm";
+
+#[derive(PartialEq, Debug)]
+pub enum Macro {
+    /// start recording the lines of a macro
+    Define(String),
+    /// stop recording
+    End,
+    /// (name, semitones added to the transposition, BPM while the lines are played)
+    Call(String, i32, Option<u16>),
+}
+
+/// check if a line should be parsed as macro, i.e. it starts with define or call, or it is a single end
+/// (otherwise it's a comment, e.g. `end of intro`)
+pub fn should_be_macro(line: &str) -> bool {
+    let mut tokens = line.split_ascii_whitespace();
+    match tokens.next() {
+        Some(DEFINE | CALL) => true,
+        Some(END) => tokens.next().is_none(),
+        _ => false,
+    }
+}
+
+/// parse a line as macro
+fn parse_macro(mut tokens: SplitAsciiWhitespace) -> Result<Macro, String> {
+    match tokens.next() {
+        Some(DEFINE) => match (tokens.next(), tokens.next()) {
+            (Some(name), None) => Ok(Macro::Define(name.to_string())),
+            _ => Err("define expects one name".to_string()),
+        }
+        Some(END) => match tokens.next() {
+            None => Ok(Macro::End),
+            Some(token) => Err(format!("end expects nothing, found {}", token)),
+        }
+        Some(CALL) => {
+            let name = tokens.next().ok_or("call expects a name")?.to_string();
+            let (mut transpose, mut bpm) = (0, None);
+            // optional parameters, in any order
+            while let Some(parameter) = tokens.next() {
+                let value = tokens.next().ok_or_else(|| format!("missing value of {}", parameter))?;
+                match parameter {
+                    TRANSPOSE => transpose = parse_interval(value)?,
+                    BPM => bpm = Some(value.parse::<u16>().ok().filter(|&bpm| bpm > 0).ok_or_else(
+                        || format!("invalid token as BPM: {}", value)
+                    )?),
+                    _ => return Err(format!("invalid parameter of call: {}", parameter)),
+                }
+            }
+            Ok(Macro::Call(name, transpose, bpm))
+        }
+        token => Err(format!("invalid token as macro: {:?}", token)),
+    }
+}
+
+pub struct MacroParser {
+    /// lines of each macro
+    macros: HashMap<String, Rc<Vec<String>>>,
+    /// name and lines of the macro being defined
+    recording: Option<(String, Vec<String>)>,
+    /// names of the macros being called, innermost last
+    calls: Vec<String>,
+}
+
+impl MacroParser {
+    pub fn new() -> Self {
+        Self { macros: HashMap::new(), recording: None, calls: Vec::new() }
+    }
+    /// parse a line as macro
+    pub fn parse(&self, tokens: SplitAsciiWhitespace) -> Result<Macro, String> {
+        parse_macro(tokens)
+    }
+    /// return if the lines are being recorded into a macro
+    pub fn on_rec(&self) -> bool {
+        self.recording.is_some()
+    }
+    /// start recording the lines of a macro
+    pub fn define(&mut self, name: String) -> Result<(), String> {
+        if self.calls.contains(&name) {
+            return Err(format!("macro {} is defined while being called", name));
+        }
+        self.recording = Some((name, Vec::new()));
+        Ok(())
+    }
+    /// record a line into the macro being defined, until the end of the macro
+    pub fn record(&mut self, line: &str) -> Result<(), String> {
+        let (name, mut lines) = self.recording.take().ok_or("recording outside of macro")?;
+        // a call is only played with the macro, but its parameters are checked now
+        match should_be_macro(line).then(|| parse_macro(line.split_ascii_whitespace())).transpose()? {
+            Some(Macro::End) => {
+                self.macros.insert(name, Rc::new(lines));
+            }
+            Some(Macro::Define(_)) => return Err(format!("macro defined inside macro {}", name)),
+            _ => {
+                lines.push(line.to_string());
+                self.recording = Some((name, lines));
+            }
+        }
+        Ok(())
+    }
+    /// start calling a macro, return its lines
+    pub fn enter(&mut self, name: &str) -> Result<Rc<Vec<String>>, String> {
+        if self.calls.iter().any(|call| call == name) {
+            return Err(format!("macro {} calls itself", name));
+        }
+        let lines = self.macros.get(name).ok_or_else(|| format!("macro {} is not defined", name))?;
+        self.calls.push(name.to_string());
+        Ok(Rc::clone(lines))
+    }
+    /// stop calling the innermost macro
+    pub fn leave(&mut self) {
+        self.calls.pop();
+    }
+    /// make sure there's no macro waiting for its end
+    pub fn finish(&self) -> Result<(), String> {
+        match &self.recording {
+            Some((name, _)) => Err(format!("macro {} does not end", name)),
+            None => Ok(()),
+        }
+    }
+}
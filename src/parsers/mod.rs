@@ -3,3 +3,8 @@ pub mod note;
 pub mod input;
 pub mod capture;
 pub mod repeat;
+pub mod directive;
+pub mod dynamic;
+pub mod scala;
+pub mod macros;
+pub mod navigation;
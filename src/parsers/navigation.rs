@@ -0,0 +1,152 @@
+use std::str::SplitAsciiWhitespace;
+
+use crate::stores::note::Line;
+
+const SEGNO: &str = "segno";
+const CODA: &str = "coda";
+const TO: &str = "to";
+const FINE: &str = "fine";
+const DA_CAPO: &str = "d.c.";
+const DAL_SEGNO: &str = "d.s.";
+const AL: &str = "al";
+
+/// where the piece is played until after a jump
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Until {
+    /// the jump itself
+    End,
+    /// the fine, where the piece ends
+    Fine,
+    /// the to coda, then the coda that follows the jump
+    Coda,
+}
+
+#[derive(PartialEq, Debug)]
+pub enum Nav {
+    /// where D.S. goes back to
+    Segno,
+    /// where the piece continues after the to coda
+    Coda,
+    /// where the piece leaves for the coda after al Coda
+    ToCoda,
+    /// where the piece ends after al Fine
+    Fine,
+    /// D.C., go back to the start
+    DaCapo(Until),
+    /// D.S., go back to the segno
+    DalSegno(Until),
+}
+
+/// check if a line should be parsed as navigation, i.e. it starts like a jump or the whole line is a marker
+/// (otherwise it's a comment, e.g. `to do`, but a malformed jump like `D.S al Fine` is an error)
+pub fn should_be_nav(line: &str) -> bool {
+    let first = line.split_ascii_whitespace().next().map(|token| token.to_ascii_lowercase());
+    let jump = |token: &str| [DA_CAPO, DAL_SEGNO].iter().any(|jump| token.starts_with(jump.trim_end_matches('.')));
+    first.is_some_and(|token| jump(&token)) || parse_nav(line.split_ascii_whitespace()).is_ok()
+}
+
+/// parse a line as navigation
+fn parse_nav(tokens: SplitAsciiWhitespace) -> Result<Nav, String> {
+    let tokens = tokens.map(|token| token.to_ascii_lowercase()).collect::<Vec<String>>();
+    let tokens = tokens.iter().map(|token| token.as_str()).collect::<Vec<&str>>();
+    match tokens[..] {
+        [SEGNO] => Ok(Nav::Segno),
+        [CODA] => Ok(Nav::Coda),
+        [TO, CODA] => Ok(Nav::ToCoda),
+        [FINE] => Ok(Nav::Fine),
+        [DA_CAPO, ref until @ ..] => Ok(Nav::DaCapo(parse_until(until)?)),
+        [DAL_SEGNO, ref until @ ..] => Ok(Nav::DalSegno(parse_until(until)?)),
+        _ => Err(format!("invalid line as navigation: {}", tokens.join(" "))),
+    }
+}
+
+pub struct NavigationParser {
+    /// every line played before, in order
+    piece: Vec<Line>,
+    /// index of the first line after each marker
+    segno: Option<usize>,
+    to_coda: Option<usize>,
+    fine: Option<usize>,
+    /// Coda while waiting for the coda, Fine once the piece has ended
+    after: Option<Until>,
+}
+
+impl NavigationParser {
+    pub fn new() -> Self {
+        Self { piece: Vec::new(), segno: None, to_coda: None, fine: None, after: None }
+    }
+    /// parse a line as navigation
+    pub fn parse(&self, tokens: SplitAsciiWhitespace) -> Result<Nav, String> {
+        parse_nav(tokens)
+    }
+    /// mark a position or jump, return the lines to be played again
+    pub fn apply(&mut self, nav: Nav) -> Result<Vec<Line>, String> {
+        let here = Some(self.piece.len());
+        let (from, until) = match nav {
+            Nav::Segno => return mark(&mut self.segno, here, "segno"),
+            Nav::ToCoda => return mark(&mut self.to_coda, here, "to coda"),
+            Nav::Fine => return mark(&mut self.fine, here, "fine"),
+            Nav::Coda => {
+                // the coda can also be reached without a jump
+                if self.after == Some(Until::Coda) {
+                    self.after = None;
+                }
+                return Ok(Vec::new());
+            }
+            Nav::DaCapo(until) => (0, until),
+            Nav::DalSegno(until) => (self.segno.ok_or("D.S. without segno")?, until),
+        };
+        if let Some(after) = self.after {
+            return Err(format!("jump while waiting for the {:?}", after));
+        }
+        let to = match until {
+            Until::End => self.piece.len(),
+            Until::Fine => self.fine.ok_or("al Fine without fine")?,
+            Until::Coda => self.to_coda.ok_or("al Coda without to coda")?,
+        };
+        if to < from {
+            return Err(format!("the {:?} is before where the jump goes back to", until));
+        }
+        if until != Until::End {
+            self.after = Some(until);
+        }
+        Ok(self.piece[from..to].to_vec())
+    }
+    /// add a new line played for the first time
+    pub fn push(&mut self, line: &Line) -> Result<(), String> {
+        match self.after {
+            Some(Until::Fine) => Err("line after the piece ends at the fine".to_string()),
+            Some(Until::Coda) => Err("line between al Coda and the coda".to_string()),
+            _ => {
+                self.piece.push(line.clone());
+                Ok(())
+            }
+        }
+    }
+    /// make sure there's no jump waiting for its coda
+    pub fn finish(&self) -> Result<(), String> {
+        match self.after {
+            Some(Until::Coda) => Err("coda is not found after al Coda".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// parse the tokens after D.C. or D.S.
+fn parse_until(tokens: &[&str]) -> Result<Until, String> {
+    match *tokens {
+        [] => Ok(Until::End),
+        [AL, FINE] => Ok(Until::Fine),
+        [AL, CODA] => Ok(Until::Coda),
+        _ => Err(format!("expected al fine | al coda, found {}", tokens.join(" "))),
+    }
+}
+
+/// mark a position once
+fn mark(marker: &mut Option<usize>, here: Option<usize>, name: &str) -> Result<Vec<Line>, String> {
+    if marker.is_some() {
+        return Err(format!("{} is marked twice", name));
+    }
+    *marker = here;
+    Ok(Vec::new())
+}
@@ -1,111 +1,422 @@
 use std::collections::HashMap;
+use std::ops::RangeInclusive;
+
+use crate::stores::note::{Articulation, Grace, Ornament, Pitch, Slur};
+use crate::stores::tuning::{System, Tuning};
 
 const TIE: u8 = b'+';
+const TUPLET: u8 = b':';
 const DOTTED: u8 = b'.';
 const STACCATO: u8 = b'*';
+const ACCENT: u8 = b'>';
+const TENUTO: u8 = b'-';
+const MARCATO: u8 = b'^';
+const FERMATA: u8 = b'@';
+const SLUR_START: u8 = b'(';
+const SLUR_END: u8 = b')';
 const REST: u8 = b'\\';
-const TONES: [(&str, i32); 17] = [
-    ("c", -8),
-    ("c#", -7), ("db", -7),
-    ("d", -6),
-    ("d#", -5), ("eb", -5),
-    ("e", -4),
-    ("f", -3),
-    ("f#", -2), ("gb", -2),
-    ("g", -1),
-    ("g#", 0), ("ab", 0),
-    ("a", 1),
-    ("a#", 2), ("bb", 2),
-    ("b", 3),
+const APPOGGIATURA: char = '\'';
+const ACCIACCATURA: char = '/';
+const GLISSANDO: char = '~';
+const CENTS: char = 'c';
+const MIDI: char = 'm';
+const HERTZ: &str = "hz";
+/// key numbers (MIDI) that can be played, C-1 to G9
+const KEYS: RangeInclusive<i32> = 0..=127;
+/// natural tones in order (to find the neighbours of ornaments) and their key numbers (MIDI) in octave 0
+const LETTERS: [(&str, i32); 7] = [("c", 12), ("d", 14), ("e", 16), ("f", 17), ("g", 19), ("a", 21), ("b", 23)];
+/// number of fifths of each natural tone above C, to find the key signature
+const FIFTHS: [i32; 7] = [0, 2, 4, -1, 1, 3, 5];
+const MINOR: char = 'm';
+/// accidentals after the natural tone, (semitones, cents) added to it
+const ACCIDENTALS: [(&str, (i32, i32)); 10] = [
+    ("", (0, 0)),
+    ("n", (0, 0)),
+    ("#", (1, 0)),
+    ("b", (-1, 0)),
+    ("x", (2, 0)),
+    ("bb", (-2, 0)),
+    // quarter tones
+    ("qs", (0, 50)),
+    ("qf", (0, -50)),
+    ("tqs", (1, 50)),
+    ("tqf", (-1, -50)),
+];
+const ORNAMENTS: [(&str, Ornament); 4] = [
+    ("tr", Ornament::Trill),
+    ("um", Ornament::UpperMordent),
+    ("lm", Ornament::LowerMordent),
+    ("tn", Ornament::Turn),
 ];
 
+/// every tone (e.g. `eb`), its key number in octave 0 and its cents on top of that
+fn tones() -> impl Iterator<Item=(String, (i32, i32))> {
+    LETTERS.iter().flat_map(|&(letter, key)| ACCIDENTALS.iter().map(
+        move |&(accidental, (semitones, cents))| (format!("{}{}", letter, accidental), (key + semitones, cents))
+    ))
+}
+
+/// number of semitones of a tone without quarter tones (e.g. `eb`) above C, from 0 to 11
+pub fn parse_tone(token: &str) -> Option<i32> {
+    let mut chars = token.chars();
+    let token = format!("{}{}", chars.next()?.to_ascii_lowercase(), chars.as_str());
+    tones().find(|(t, (_, cents))| *t == token && *cents == 0).map(|(_, (key, _))| (key - LETTERS[0].1).rem_euclid(12))
+}
+
+/// number of sharps (positive) or flats (negative) of a key, e.g. `Eb` (major) or `f#m` (minor)
+pub fn parse_key(token: &str) -> Option<i32> {
+    let (tonic, minor) = match token.strip_suffix(MINOR) {
+        Some(tonic) => (tonic, true),
+        None => (token, false),
+    };
+    let mut chars = tonic.chars();
+    let letter = chars.next()?.to_ascii_lowercase();
+    let letter = LETTERS.iter().position(|(l, _)| l.starts_with(letter))?;
+    let accidental = match chars.as_str() {
+        "" => 0,
+        "#" => 7,
+        "b" => -7,
+        _ => return None,
+    };
+    // the relative major is 3 fifths below the minor
+    Some(FIFTHS[letter] + accidental - if minor { 3 } else { 0 }).filter(|fifths| (-7..=7).contains(fifths))
+}
+
 #[derive(PartialEq)]
 pub enum Note {
-    /// (number of beats, staccato)
-    Len(f64, bool),
-    /// Hz
-    Pitch(f64),
+    /// (number of beats played, number of beats occupied, articulation)
+    Len(f64, f64, Articulation),
+    /// Hz and ornament
+    Pitch(Pitch),
 }
 
 pub struct NoteParser {
-    notes: HashMap<usize, f64>,
-    tones: HashMap<String, i32>,
+    /// frequency of each key number and cent offset
+    notes: HashMap<(i32, i32), f64>,
+    tones: HashMap<String, (i32, i32)>,
+    /// semitones added to each natural tone by the key signature
+    key: [i32; 7],
+    /// is a key signature set, only then accidentals last until the end of the bar
+    keyed: bool,
+    /// accidentals written in the current bar, (semitones, cents) of each natural tone and octave
+    bar: HashMap<(usize, i32), (i32, i32)>,
+    /// semitones added to every pitch
+    transpose: i32,
+    /// number of ornament notes per second
+    speed: f64,
+    tuning: Tuning,
 }
 
 impl NoteParser {
     pub fn new() -> Self {
         Self {
             notes: HashMap::new(),
-            tones: TONES.iter().map(|(t, i)| (t.to_string(), *i)).collect(),
+            tones: tones().collect(),
+            key: [0; 7],
+            keyed: false,
+            bar: HashMap::new(),
+            transpose: 0,
+            speed: 12.0,
+            tuning: Tuning::default(),
+        }
+    }
+    /// change the key signature, given the number of sharps (positive) or flats (negative)
+    pub fn set_key(&mut self, fifths: i32) {
+        self.key = [0; 7];
+        for n in 0..fifths.unsigned_abs() as usize {
+            // sharps are added in the order of f c g d a e b, flats in reverse
+            let letter = if fifths > 0 { (3 + 4 * n) % 7 } else { (6 + 3 * n) % 7 };
+            self.key[letter] = fifths.signum();
         }
+        self.keyed = true;
+        self.bar.clear();
+    }
+    /// number of semitones added to every pitch
+    pub fn transpose(&self) -> i32 {
+        self.transpose
+    }
+    /// change the number of semitones added to every pitch
+    pub fn set_transpose(&mut self, semitones: i32) {
+        self.transpose = semitones;
+    }
+    /// forget the accidentals of the current bar
+    pub fn end_bar(&mut self) {
+        self.bar.clear();
+    }
+    /// change the Hz of the reference key
+    pub fn set_reference(&mut self, reference: f64) {
+        self.tuning.reference = reference;
+        self.notes.clear();
+    }
+    /// change the tuning system, and the Hz of the reference key if given
+    pub fn set_tuning(&mut self, system: System, reference: Option<f64>) {
+        self.tuning = Tuning { reference: reference.unwrap_or(self.tuning.reference), system };
+        self.notes.clear();
+    }
+    /// change the number of ornament notes per second
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
     }
     /// try parse token as note length or frequency
     pub fn try_parse(&mut self, token: &str) -> Result<Option<Note>, String> {
-        Ok(if token.as_bytes()[0].is_ascii_digit() {
-            let (beat, staccato) = self.length(token)?;
-            Some(Note::Len(beat, staccato))
+        Ok(if token.as_bytes()[0].is_ascii_digit() && !token.ends_with(HERTZ) {
+            let (token, articulation) = self.articulation(token)?;
+            let (length, size) = self.length(token)?;
+            Some(Note::Len(length, size, articulation))
+        } else if let Some((grace, note)) = self.grace(token) {
+            match self.try_parse(note)? {
+                Some(Note::Pitch(pitch)) if pitch.frequency != 0.0 && pitch.grace.is_none() => Some(Note::Pitch(Pitch {
+                    grace: Some(grace),
+                    ..pitch
+                })),
+                _ => return Err(format!("invalid token as grace note: {}", token)),
+            }
+        } else if let Some((from, to)) = self.glissando(token) {
+            Some(Note::Pitch(Pitch {
+                glide: Some(self.frequency(to)?),
+                ..self.pitch(from)?
+            }))
+        } else if let Some((note, ornament)) = self.ornament(token) {
+            Some(Note::Pitch(Pitch {
+                ornament: Some((ornament, self.neighbour(note, 1)?, self.neighbour(note, -1)?)),
+                speed: self.speed,
+                ..self.pitch(note)?
+            }))
         } else if self.is_pitch(token) {
-            Some(Note::Pitch(self.frequency(token)?))
+            Some(Note::Pitch(self.pitch(token)?))
         } else if self.is_rest(token) {
-            Some(Note::Pitch(0.0))
+            Some(Note::Pitch(Pitch::new(0.0)))
         } else {
             None
         })
     }
-    /// parse token as number of beats
-    /// returns length and if staccato
-    fn length(&self, token: &str) -> Result<(f64, bool), String> {
-        match token.parse::<usize>() {
-            // normal note value
-            Ok(length) => Ok((1.0 / length as f64, false)),
-            Err(..) => match token.as_bytes().last() {
-                Some(&DOTTED) => Ok((self.scale(token, 1.5, DOTTED), false)),
-                Some(&STACCATO) => Ok((self.scale(token, 0.5, STACCATO), true)),
-                _ if self.has_tie(token) => Ok((self.parse_tie(token), false)),
-                _ => Err(format!("invalid token as note length: {}", token)),
+    /// parse token as length expression, i.e. terms joined by ties (e.g. `2.+8*`)
+    /// returns number of beats played and number of beats occupied
+    fn length(&self, token: &str) -> Result<(f64, f64), String> {
+        let terms: Vec<&str> = token.split(TIE as char).collect();
+        terms.iter().enumerate().try_fold((0.0, 0.0), |(length, size), (i, term)| {
+            let (l, s) = self.term(term)?;
+            // a gap in the middle of a tie would not be a tie anymore
+            if l != s && i != terms.len() - 1 {
+                Err(format!("only the last note of a tie can be staccato: {}", token))
+            } else {
+                Ok((length + l, size + s))
+            }
+        })
+    }
+    /// parse a term of length expression as note value followed by dots and staccato (e.g. `4..*`)
+    /// returns number of beats played and number of beats occupied
+    fn term(&self, term: &str) -> Result<(f64, f64), String> {
+        let (term, staccato) = match term.strip_suffix(STACCATO as char) {
+            Some(term) => (term, true),
+            None => (term, false),
+        };
+        let value = term.trim_end_matches(DOTTED as char);
+        // each dot adds half of the previous duration: 1.5x, 1.75x, 1.875x...
+        let dots = (term.len() - value.len()) as i32;
+        let beat = self.value(value)? * (2.0 - 0.5f64.powi(dots));
+        // staccato has half length but keeps the size that it occupies
+        Ok(if staccato { (beat / 2.0, beat) } else { (beat, beat) })
+    }
+    /// parse token as note value (e.g. `8`) or tuplet (e.g. `8:3`, `8:5:4`)
+    /// returns number of beats
+    fn value(&self, token: &str) -> Result<f64, String> {
+        let invalid = || format!("invalid token as note length: {}", token);
+        let numbers = token.split(TUPLET as char).map(
+            |s| s.parse::<usize>().ok().filter(|&n| n > 0)
+        ).collect::<Option<Vec<usize>>>().ok_or_else(invalid)?;
+        // (note value, number of notes, number of note values they take the time of)
+        let (value, actual, normal) = match *numbers.as_slice() {
+            [value] => (value, 1, 1),
+            [_, 1] => return Err(invalid()),
+            // duplets (2:3), quadruplets (4:3), octuplets (8:6)...
+            [value, actual] if actual.is_power_of_two() => (value, actual, (actual * 3 / 4).max(3)),
+            // otherwise the largest power of 2 that is smaller, e.g. triplets (3:2)
+            [value, actual] => (value, actual, actual.next_power_of_two() / 2),
+            [value, actual, normal] => (value, actual, normal),
+            _ => return Err(invalid()),
+        };
+        // one division only, so that the beats add up exactly
+        Ok(normal as f64 / (value * actual) as f64)
+    }
+    /// strip the articulation suffixes of a token as length (in any order)
+    /// returns the rest of the token and the articulation
+    fn articulation<'a>(&self, mut token: &'a str) -> Result<(&'a str, Articulation), String> {
+        let mut articulation = Articulation::default();
+        while let Some(&last) = token.as_bytes().last() {
+            match (last, articulation.slur) {
+                (ACCENT, _) => articulation.accent = true,
+                (TENUTO, _) => articulation.tenuto = true,
+                (MARCATO, _) => articulation.marcato = true,
+                (FERMATA, _) => articulation.fermata = true,
+                (SLUR_START, None) => articulation.slur = Some(Slur::Start),
+                (SLUR_END, None) => articulation.slur = Some(Slur::End),
+                (SLUR_START | SLUR_END, Some(_)) => return Err(format!("a chord can only start or end one slur: {}", token)),
+                _ => break,
             }
+            token = &token[..token.len() - 1];
         }
+        Ok((token, articulation))
+    }
+    /// strip the grace note prefix of a token as pitch (a bare prefix is not a grace note)
+    fn grace<'a>(&self, token: &'a str) -> Option<(Grace, &'a str)> {
+        if let Some(note) = token.strip_prefix(APPOGGIATURA) {
+            Some((Grace::Appoggiatura, note))
+        } else {
+            token.strip_prefix(ACCIACCATURA).map(|note| (Grace::Acciaccatura, note))
+        }.filter(|(_, note)| !note.is_empty())
+    }
+    /// split a token as glissando into the pitches it slides from and to
+    fn glissando<'a>(&self, token: &'a str) -> Option<(&'a str, &'a str)> {
+        token.split_once(GLISSANDO).filter(|(from, to)| self.is_pitch(from) && self.is_pitch(to))
+    }
+    /// strip the ornament suffix of a token as pitch
+    /// returns the rest of the token and the ornament, if the rest is a pitch
+    fn ornament<'a>(&self, token: &'a str) -> Option<(&'a str, Ornament)> {
+        ORNAMENTS.iter().find_map(|&(suffix, ornament)| {
+            token.strip_suffix(suffix).filter(|note| self.is_pitch(note)).map(|note| (note, ornament))
+        })
+    }
+    /// frequency of the natural note `step` letters above (or below) a note
+    fn neighbour(&mut self, note: &str, step: i32) -> Result<f64, String> {
+        let invalid = || format!("ornament needs a note name: {}", note);
+        let (tone, octave, _) = self.split(note).ok_or_else(invalid)?;
+        let tone = tone.to_ascii_lowercase();
+        let letter = LETTERS.iter().position(|&(l, _)| tone.starts_with(l)).ok_or_else(invalid)? as i32 + step;
+        let octave = octave.parse::<i32>().map_err(|_| invalid())? + letter.div_euclid(7);
+        self.frequency(&format!("{}{}", LETTERS[letter.rem_euclid(7) as usize].0, octave))
+    }
+    /// move a pitch by a number of semitones
+    pub fn shift(&mut self, pitch: &Pitch, semitones: i32) -> Result<Pitch, String> {
+        match pitch.key {
+            Some((key, _)) => self.retune(pitch, key + semitones),
+            // Hz and rest
+            None => Ok(pitch.scale(2f64.powf(semitones as f64 / 12.0))),
+        }
+    }
+    /// move a pitch by a number of degrees of the scale of the key signature
+    /// a pitch outside of the scale keeps its distance to the degree below
+    pub fn step(&mut self, pitch: &Pitch, steps: i32) -> Result<Pitch, String> {
+        if pitch.frequency == 0.0 { return Ok(*pitch); }
+        let (key, _) = pitch.key.ok_or("diatonic step needs a note name or MIDI note number")?;
+        // key number of each degree in octave 0
+        let degree = |letter: i32| LETTERS[letter.rem_euclid(7) as usize].1
+            + self.key[letter.rem_euclid(7) as usize] + 12 * letter.div_euclid(7);
+        // the closest degree below (or at) the pitch, and the distance to it
+        let (letter, offset) = (0..7).map(
+            |letter| (letter, (key - degree(letter)).rem_euclid(12))
+        ).min_by_key(|&(_, offset)| offset).unwrap();
+        let base = key - offset;
+        // key number of the degree `step` letters above (or below) the new one
+        let neighbour = |step: i32| base + degree(letter + steps + step) - degree(letter);
+        let (upper, lower) = (neighbour(1), neighbour(-1));
+        let stepped = self.retune(pitch, neighbour(0) + offset)?;
+        // the neighbours of the ornament follow the new letter, e.g. a trill on e4 one step up trills f4 and g4
+        Ok(match stepped.ornament {
+            Some((ornament, _, _)) => Pitch {
+                ornament: Some((ornament, self.tuned(upper, 0)?, self.tuned(lower, 0)?)),
+                ..stepped
+            },
+            None => stepped,
+        })
+    }
+    /// the same pitch at another key number, keeping its cents
+    fn retune(&mut self, pitch: &Pitch, key: i32) -> Result<Pitch, String> {
+        let cents = pitch.key.map_or(0, |(_, cents)| cents);
+        let frequency = self.tuned(key, cents)?;
+        // the neighbours of the ornament and the glissando follow by the same ratio (see `step` for ornaments)
+        Ok(Pitch { frequency, key: Some((key, cents)), ..pitch.scale(frequency / pitch.frequency) })
+    }
+    /// parse token as pitch without ornament
+    fn pitch(&mut self, token: &str) -> Result<Pitch, String> {
+        // raw frequency, regardless of the tuning
+        if let Some(hz) = token.strip_suffix(HERTZ) {
+            return hz.parse::<f64>().ok().filter(|hz| *hz > 0.0 && hz.is_finite()).map(
+                |hz| Pitch::new(hz * 2f64.powf(self.transpose as f64 / 12.0))
+            ).ok_or_else(|| format!("invalid token as note frequency: {}", token));
+        }
+        let (key_num, cents) = self.key_number(token)?;
+        Ok(Pitch { key: Some((key_num, cents)), ..Pitch::new(self.tuned(key_num, cents)?) })
     }
     /// parse token as frequency
     fn frequency(&mut self, token: &str) -> Result<f64, String> {
-        // https://en.wikipedia.org/wiki/Piano_key_frequencies
-        let key_num = self.key_number(token)?;
-        Ok(*self.notes.entry(key_num).or_insert(
-            2f64.powf((key_num as f64 - 49.0) / 12.0) * 440.0
-        ))
+        self.pitch(token).map(|pitch| pitch.frequency)
+    }
+    /// frequency of a key number (MIDI) and cent offset in the current tuning
+    fn tuned(&mut self, key_num: i32, cents: i32) -> Result<f64, String> {
+        if !KEYS.contains(&key_num) {
+            return Err(format!("key {} is out of range (C-1 to G9)", key_num));
+        }
+        match self.notes.get(&(key_num, cents)) {
+            Some(&frequency) => Ok(frequency),
+            None => {
+                let frequency = self.tuning.frequency(key_num)? * 2f64.powf(cents as f64 / 1200.0);
+                self.notes.insert((key_num, cents), frequency);
+                Ok(frequency)
+            }
+        }
+    }
+    /// split a token as pitch into its tone, octave and cent offset (e.g. `a4+15c` into `a`, `4` and 15)
+    fn split<'a>(&self, token: &'a str) -> Option<(&'a str, &'a str, i32)> {
+        let (note, cents) = match token.strip_suffix(CENTS) {
+            Some(rest) => {
+                let sign = rest.rfind(['+', '-'])?;
+                (&rest[..sign], rest[sign..].parse::<i32>().ok()?)
+            }
+            None => (token, 0),
+        };
+        // the octave can be negative or have multiple digits
+        let (tone, octave) = note.split_at(note.find(|c: char| c.is_ascii_digit() || c == '-')?);
+        Some((tone, octave, cents)).filter(|_| self.tone(tone).is_some())
+    }
+    /// key number (MIDI) in octave 0 and cents of a tone, the letter can be uppercase (e.g. `Eb`)
+    fn tone(&self, tone: &str) -> Option<(i32, i32)> {
+        let mut chars = tone.chars();
+        let letter = chars.next()?.to_ascii_lowercase();
+        self.tones.get(&format!("{}{}", letter, chars.as_str())).copied()
     }
 
-    /// check if token is a frequency
+    /// check if token is a frequency, i.e. a note name, a MIDI note number (e.g. `m60`) or Hz (e.g. `440hz`)
     fn is_pitch(&self, token: &str) -> bool {
-        if token.is_empty() {
-            false
-        } else {
-            self.tones.contains_key(&token[..token.len() - 1])
-        }
+        self.split(token).is_some()
+            || token.strip_prefix(MIDI).is_some_and(|key| key.parse::<i32>().is_ok())
+            || token.strip_suffix(HERTZ).is_some_and(|hz| hz.parse::<f64>().is_ok())
     }
     /// check if token is rest
     fn is_rest(&self, token: &str) -> bool {
-        token.as_bytes() == &[REST]
-    }
-    /// check of token as length has tie
-    fn has_tie(&self, token: &str) -> bool {
-        token.bytes().all(|ch| ch.is_ascii_digit() || ch == TIE)
-    }
-    /// parse token as tie
-    fn parse_tie(&self, token: &str) -> f64 {
-        // sum up each value
-        token.split(TIE as char).flat_map(|s| s.parse::<f64>()).map(|f| 1.0 / f).sum()
-    }
-    /// scale the duration of a token as length
-    fn scale(&self, token: &str, scale: f64, suffix: u8) -> f64 {
-        scale / token.strip_suffix(suffix as char).unwrap().parse::<f64>().unwrap()
-    }
-    /// convert note to key number
-    fn key_number(&self, note: &str) -> Result<usize, String> {
-        let (tone, octave) = note.split_at(note.len() - 1);
-        match (self.tones.get(tone), octave.parse::<i32>()) {
-            (Some(i), Ok(o)) => Ok((i + o * 12) as usize),
-            _ => Err(format!("invalid token as note frequency: {}", note)),
+        token.as_bytes() == [REST]
+    }
+    /// convert note name or MIDI note number to key number (MIDI) and cent offset, after transposition
+    /// a note name without accidental follows the accidentals of the bar (once a key is set), then the key signature
+    fn key_number(&mut self, note: &str) -> Result<(i32, i32), String> {
+        let invalid = || format!("invalid token as note frequency: {}", note);
+        let (key, cents) = match note.strip_prefix(MIDI) {
+            Some(key) => (key.parse::<i32>().map_err(|_| invalid())?, 0),
+            None => {
+                let (tone, octave, cents) = self.split(note).ok_or_else(invalid)?;
+                let (key, accidental) = self.tone(tone).ok_or_else(invalid)?;
+                let octave = octave.parse::<i32>().map_err(|_| invalid())?;
+                let letter = LETTERS.iter().position(|&(l, _)| tone.to_ascii_lowercase().starts_with(l)).ok_or_else(invalid)?;
+                let natural = LETTERS[letter].1;
+                let (semitones, accidental) = if tone.len() > 1 {
+                    // an explicit accidental lasts until the end of the bar, but only in a key
+                    // so that files without key directive sound as before
+                    if self.keyed {
+                        self.bar.insert((letter, octave), (key - natural, accidental));
+                    }
+                    (key - natural, accidental)
+                } else {
+                    self.bar.get(&(letter, octave)).copied().unwrap_or((self.key[letter], 0))
+                };
+                let key = octave.checked_mul(12).and_then(|o| o.checked_add(natural + semitones));
+                (key.ok_or_else(invalid)?, accidental + cents)
+            }
+        };
+        let key = key + self.transpose;
+        if KEYS.contains(&key) {
+            Ok((key, cents))
+        } else {
+            Err(format!("note is out of range (C-1 to G9): {}", note))
         }
     }
 }
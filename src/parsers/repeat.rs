@@ -7,6 +7,7 @@ use crate::stores::note::Line;
 const REPEAT: u8 = b'|';
 const DELIM: u8 = b':';
 const SEP: u8 = b'.';
+const TIMES: u8 = b'x';
 
 #[derive(PartialEq, Debug)]
 pub enum Rep {
@@ -14,16 +15,16 @@ pub enum Rep {
     RepeatStart,
     /// e.g. |1.3.
     VoltaStart(Vec<usize>),
-    /// :|
-    RepeatEnd,
+    /// :| or e.g. :|x4 (play the section 4 times)
+    RepeatEnd(Option<usize>),
     /// |
     VoltaEnd,
 }
 
-/// check if a line should be parsed as repeat based on the first token
+/// check if a token should be parsed as repeat
 pub fn should_be_rep(token: &str) -> bool {
     let bytes = token.as_bytes();
-    bytes.contains(&REPEAT) && bytes.iter().all(
+    bytes.starts_with(&[DELIM, REPEAT, TIMES]) || bytes.contains(&REPEAT) && bytes.iter().all(
         |&b| b.is_ascii_digit() || matches!(b, REPEAT | DELIM | SEP)
     )
 }
@@ -38,40 +39,124 @@ fn not_found(v: usize, action: &str) -> Result<(), String> {
     Err(format!("{} is not found while trying to {}", volta, action))
 }
 
+/// parse the digits of a repeat count or a volta number, at least 1
+fn parse_number(digits: &[u8]) -> Option<usize> {
+    std::str::from_utf8(digits).ok()
+        .filter(|digits| !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit()))
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|&number| number > 0)
+}
+
+/// parse the numbers of a volta start, each followed by a dot (e.g. `|1.3.`, `|10.`)
 fn parse_volta_start(bytes: &[u8]) -> Option<Vec<usize>> {
-    match bytes.strip_prefix(&[REPEAT]) {
-        Some(voltas) => Some(voltas.iter().filter_map(
-            |&b| if b == SEP { None } else { Some((b - b'0') as usize) }
-        ).collect()),
-        None => None
-    }
+    let numbers = bytes.strip_prefix(&[REPEAT])?.strip_suffix(&[SEP])?;
+    numbers.split(|&b| b == SEP).map(parse_number).collect()
 }
 
-#[derive(Default)]
-pub struct RepeatParser {
+/// a repeat section being recorded
+pub struct Section {
     /// 0 for pre-volta, MAX for post-volta
     voltas: BTreeMap<usize, Rc<RefCell<Vec<Line>>>>,
     /// indices of one of the voltas to record
     current: usize,
     /// should trigger repeat on RepeatEnd
     on_rep_end: bool,
+    /// line number of the trigger that ended it, to be repeated unless voltas follow
+    ended: Option<usize>,
+    /// number of times the section is played, if given by RepeatEnd (or once validated)
+    times: Option<usize>,
 }
 
-impl RepeatParser {
-    pub fn new() -> Self {
+impl Section {
+    fn new() -> Self {
         Self {
             voltas: BTreeMap::new(),
             current: 0,
             on_rep_end: true,
+            ended: None,
+            times: None,
         }
     }
+    /// init new voltas to store, pre-volta and post-volta if empty
+    fn start(&mut self, indices: &[usize]) -> Result<(), String> {
+        // voltas after RepeatEnd, so the section goes on until VoltaEnd
+        if self.ended.is_some() && indices.iter().all(|&i| 0 < i && i < !0) {
+            self.ended = None;
+            self.on_rep_end = false;
+        }
+        let volta = Rc::new(RefCell::new(Vec::new()));
+        for &i in indices.iter() {
+            match self.voltas.get(&i) {
+                Some(_) if 0 < i && i < !0 => return Err(format!("volta no. {} is started twice", i)),
+                Some(_) => (),
+                None => { self.voltas.insert(i, Rc::clone(&volta)); }
+            }
+        }
+        self.current = indices[0];
+        Ok(())
+    }
+    /// make sure every volta to be played is there, decide how many times the section is played
+    fn validate(&mut self) -> Result<(), String> {
+        if !self.voltas.contains_key(&0) {
+            return not_found(0, "repeat");
+        }
+        let last = self.voltas.keys().filter(|&&k| 0 < k && k < !0).max().copied();
+        // played as many times as given, or as the voltas (at least twice), or twice
+        let times = match (self.times, last) {
+            (Some(times), Some(last)) if last > times => return Err(
+                format!("volta no. {} is more than the {} times of the repeat", last, times)
+            ),
+            (Some(times), _) => times,
+            (None, Some(last)) => last.max(2),
+            (None, None) => 2,
+        };
+        // each time needs its own volta, unless there's none
+        if last.is_some() {
+            if let Some(k) = (1..=times).find(|k| !self.voltas.contains_key(k)) {
+                return not_found(k, "repeat");
+            }
+        }
+        self.times = Some(times);
+        Ok(())
+    }
+    /// repeat voltas
+    pub fn repeat(&self, mut write: impl FnMut(&Line) -> Result<(), String>) -> Result<(), String> {
+        for k in 1..=self.times.unwrap_or(2) {
+            // write pre-volta (volta) post-volta
+            self.write(0, &mut write)?;
+            self.write(k, &mut write)?;
+            self.write(!0, &mut write)?;
+        }
+        Ok(())
+    }
+    /// write a volta, if any
+    fn write(&self, v: usize, write: &mut impl FnMut(&Line) -> Result<(), String>) -> Result<(), String> {
+        match self.voltas.get(&v) {
+            Some(volta) => volta.borrow().iter().try_for_each(write),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct RepeatParser {
+    /// sections being recorded, the innermost one last
+    sections: Vec<Section>,
+}
+
+impl RepeatParser {
+    pub fn new() -> Self {
+        Self { sections: Vec::new() }
+    }
     /// parse token as repeat
     pub fn parse(&self, token: &str) -> Result<Rep, String> {
         let bytes = token.as_bytes();
-        match bytes {
-            &[REPEAT] => Ok(Rep::VoltaEnd),
-            &[DELIM, REPEAT] => Ok(Rep::RepeatEnd),
-            &[REPEAT, DELIM] => Ok(Rep::RepeatStart),
+        match *bytes {
+            [REPEAT] => Ok(Rep::VoltaEnd),
+            [DELIM, REPEAT] => Ok(Rep::RepeatEnd(None)),
+            [REPEAT, DELIM] => Ok(Rep::RepeatStart),
+            [DELIM, REPEAT, TIMES, ref digits @ ..] => parse_number(digits).map(|times| Rep::RepeatEnd(Some(times))).ok_or_else(
+                || format!("invalid repeat count: {}", token)
+            ),
             // parse as volta start or die
             _ => if let Some(voltas) = parse_volta_start(bytes) {
                 Ok(Rep::VoltaStart(voltas))
@@ -82,71 +167,217 @@ impl RepeatParser {
     }
     /// return if Repeat is currently recording
     pub fn on_rec(&self) -> bool {
-        !self.voltas.is_empty()
+        !self.sections.is_empty()
     }
-    /// return the Rep token that will trigger a repeat
-    pub fn get_trigger(&self) -> Rep {
-        if self.on_rep_end {
-            Rep::RepeatEnd
-        } else {
-            Rep::VoltaEnd
+    /// return if the innermost section has ended, waiting to be repeated
+    pub fn ended(&self) -> bool {
+        self.sections.last().is_some_and(|section| section.ended.is_some())
+    }
+    /// open a new section inside the current one (if any), starting with its pre-volta
+    pub fn open(&mut self) -> Result<(), String> {
+        self.sections.push(Section::new());
+        self.start(&[0])
+    }
+    /// init new voltas of the innermost section to store, pre-volta and post-volta if empty
+    pub fn start(&mut self, indices: &[usize]) -> Result<(), String> {
+        if self.sections.is_empty() {
+            self.sections.push(Section::new());
         }
+        self.sections.last_mut().unwrap().start(indices)
     }
-    /// change the Rep token that will trigger a repeat
-    pub fn set_trigger(&mut self, trigger: Rep) -> Result<(), String> {
-        match trigger {
-            Rep::VoltaEnd => Ok(self.on_rep_end = false),
-            Rep::RepeatEnd => Ok(self.on_rep_end = true),
-            _ => Err(format!("invalid trigger token, expected VoltaEnd | RepeatEnd, found {:?}", trigger)),
+    /// start the post-volta of the innermost section and end it on line `line`, to be played `times` times
+    pub fn end(&mut self, times: Option<usize>, line: usize) -> Result<(), String> {
+        self.start(&[!0])?;
+        let section = self.sections.last_mut().unwrap();
+        match (section.times, times) {
+            (Some(old), Some(times)) if old != times => return Err(format!("repeat count changed from {} to {}", old, times)),
+            (_, Some(times)) => section.times = Some(times),
+            _ => (),
         }
+        section.ended = Some(line);
+        Ok(())
     }
-    /// init new voltas to store if empty
-    pub fn start(&mut self, indices: &[usize]) {
-        let volta = Rc::new(RefCell::new(Vec::new()));
-        for &i in indices.iter() {
-            self.voltas.entry(i).or_insert(Rc::clone(&volta));
+    /// start the post-volta of the innermost section, end it on line `line` if the voltas are on
+    /// (then nothing can follow, so the voltas are validated right away)
+    pub fn end_volta(&mut self, line: usize) -> Result<(), String> {
+        self.start(&[!0])?;
+        let section = self.sections.last_mut().unwrap();
+        if !section.on_rep_end {
+            section.ended = Some(line);
+            section.validate()?;
+        }
+        Ok(())
+    }
+    /// close the innermost section, return it to be repeated
+    pub fn close(&mut self) -> Result<Section, String> {
+        let mut section = self.sections.pop().ok_or("no repeat section to close")?;
+        match (section.validate(), section.ended) {
+            (Err(why), Some(line)) => Err(format!("{}, in the repeat that ends on line {}", why, line)),
+            (result, _) => result.map(|_| section),
+        }
+    }
+    /// make sure there's no repeat waiting for its end
+    pub fn finish(&self) -> Result<(), String> {
+        if self.on_rec() {
+            Err("repeat does not end".to_string())
+        } else {
+            Ok(())
         }
-        self.current = indices[0];
     }
-    /// add new line to current voltas
+    /// add new line to current voltas of the innermost section
     pub fn push(&mut self, line: Line) -> Result<(), String> {
         if line.size() == 0 {
-            return Err(format!("attempt to push empty line"));
+            return Err("attempt to push empty line".to_string());
         }
-        match self.voltas.get(&self.current) {
-            Some(volta) => Ok(volta.borrow_mut().push(line)),
-            None => not_found(self.current, "push new line"),
+        let section = self.sections.last().ok_or("no repeat section to push new line")?;
+        match section.voltas.get(&section.current) {
+            Some(volta) => {
+                volta.borrow_mut().push(line);
+                Ok(())
+            }
+            None => not_found(section.current, "push new line"),
         }
     }
-    /// repeat voltas and reset self
-    pub fn repeat(&self, mut write: impl FnMut(&Line) -> Result<(), String>) -> Result<(), String> {
-        if self.voltas.len() > 2 {
-            for &k in self.voltas.keys().filter(|&&k| 0 < k && k < !0) {
-                // write pre-volta volta post-volta
-                self.write(0, &mut write)?;
-                self.write(k, &mut write)?;
-                self.write(!0, &mut write)?;
-            }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stores::note::Chord;
+
+    /// a line told apart by its size, e.g. `B` is 2
+    fn line(name: u8) -> Line {
+        let mut line = Line::new();
+        line.push(Rc::new(Chord { size: (name - b'A' + 1) as usize, ..Chord::new() }));
+        line
+    }
+
+    /// record the line if repeat is on, otherwise play it (like `InputParser::emit_line`)
+    fn emit(rep: &mut RepeatParser, played: &mut Vec<char>, line: Line) -> Result<(), String> {
+        repeat(rep, played)?;
+        if rep.on_rec() {
+            rep.push(line)
         } else {
-            // no voltas, only pre-volta
-            self.write(0, &mut write)?;
-            self.write(0, &mut write)?;
+            played.push((b'A' + line.size() as u8 - 1) as char);
+            Ok(())
         }
-        Ok(())
     }
-    /// free data
-    pub fn clear(&mut self) {
-        self.voltas.clear();
-        self.current = 0;
-        self.on_rep_end = true;
+
+    /// repeat the innermost section if it has ended (like `InputParser::repeat`)
+    fn repeat(rep: &mut RepeatParser, played: &mut Vec<char>) -> Result<(), String> {
+        if !rep.ended() {
+            return Ok(());
+        }
+        let section = rep.close()?;
+        section.repeat(|line| emit(rep, played, line.clone()))
     }
-    /// write a volta
-    fn write(&self, v: usize, write: &mut impl FnMut(&Line) -> Result<(), String>) -> Result<(), String> {
-        match self.voltas.get(&v) {
-            // didn't know that you can put a for loop inside Ok()
-            Some(volta) => Ok(for line in volta.borrow().iter() { write(line)?; }),
-            None => not_found(v, "write line"),
+
+    /// play each token as a line (a capital letter) or a repeat token, the n-th token being on line n
+    fn play(input: &str) -> Result<String, String> {
+        let (mut rep, mut played) = (RepeatParser::new(), Vec::new());
+        for (i, token) in input.split_ascii_whitespace().enumerate() {
+            if !should_be_rep(token) {
+                emit(&mut rep, &mut played, line(token.as_bytes()[0]))?;
+                continue;
+            }
+            let r = rep.parse(token)?;
+            if !matches!(r, Rep::VoltaStart(_)) {
+                repeat(&mut rep, &mut played)?;
+            }
+            match r {
+                Rep::RepeatStart => rep.open(),
+                Rep::VoltaStart(vs) => rep.start(&vs),
+                Rep::RepeatEnd(times) => rep.end(times, i + 1),
+                Rep::VoltaEnd => rep.end_volta(i + 1),
+            }?;
         }
+        repeat(&mut rep, &mut played)?;
+        rep.finish()?;
+        Ok(played.iter().collect())
     }
-}
 
+    #[test]
+    fn parse_tokens() {
+        let rep = RepeatParser::new();
+        assert_eq!(rep.parse("|:"), Ok(Rep::RepeatStart));
+        assert_eq!(rep.parse(":|"), Ok(Rep::RepeatEnd(None)));
+        assert_eq!(rep.parse(":|x12"), Ok(Rep::RepeatEnd(Some(12))));
+        assert_eq!(rep.parse("|"), Ok(Rep::VoltaEnd));
+        assert_eq!(rep.parse("|1.3."), Ok(Rep::VoltaStart(vec![1, 3])));
+        assert_eq!(rep.parse("|10."), Ok(Rep::VoltaStart(vec![10])));
+        assert!(rep.parse(":|x0").is_err());
+        assert!(rep.parse("|0.").is_err());
+        assert!(rep.parse("|1").is_err());
+        assert!(rep.parse("|1..2.").is_err());
+    }
+
+    #[test]
+    fn inline_tokens() {
+        // `|: 4 c4 :|` is a repeat token, chords, then a repeat token
+        let tokens: Vec<bool> = "|: 4 c4 :|".split_ascii_whitespace().map(should_be_rep).collect();
+        assert_eq!(tokens, [true, false, false, true]);
+        assert_eq!(play("|: A :|"), Ok("AA".to_string()));
+        assert_eq!(play("|: A :| |: B |1. C :| |2. D |"), Ok("AABCBD".to_string()));
+    }
+
+    #[test]
+    fn no_voltas() {
+        assert_eq!(play("|: A B :|"), Ok("ABAB".to_string()));
+    }
+
+    #[test]
+    fn repeat_count() {
+        assert_eq!(play("|: A :|x4 B"), Ok("AAAAB".to_string()));
+        assert_eq!(play("|: A |1.2. B |3. C :|x3"), Ok("ABABAC".to_string()));
+        assert!(play("|: A |1. B |3. C :|x2").unwrap_err().contains("volta no. 3 is more than the 2 times"));
+    }
+
+    #[test]
+    fn prevolta_only() {
+        assert_eq!(play("|: A |1.3. B :| |2. C :| |4. C |"), Ok("ABACABAC".to_string()));
+        assert_eq!(play("|: A |1.3. B |2.4. C :|"), Ok("ABACABAC".to_string()));
+    }
+
+    #[test]
+    fn postvolta_only() {
+        assert_eq!(play("A |: C |1. B :| |2. D |"), Ok("ACBCD".to_string()));
+        assert_eq!(play("|: |1. A |2. B | C :| D"), Ok("ACBCD".to_string()));
+    }
+
+    #[test]
+    fn prevolta_and_postvolta() {
+        assert_eq!(play("|: A |1. B D :| |2. C D |"), Ok("ABDACD".to_string()));
+        assert_eq!(play("|: A |1. B |2. C | D :|"), Ok("ABDACD".to_string()));
+    }
+
+    #[test]
+    fn nested() {
+        assert_eq!(
+            play("|: A |: B :| C |1. D |2. E |: F |1. G :| |2. H | :|"),
+            Ok("ABBCDABBCEFGFH".to_string()),
+        );
+    }
+
+    #[test]
+    fn duplicate_volta() {
+        assert!(play("|: A |1. B |1. C :|").unwrap_err().contains("volta no. 1 is started twice"));
+        assert!(play("|: A |1. B |2.1. C :|").unwrap_err().contains("volta no. 1 is started twice"));
+    }
+
+    #[test]
+    fn missing_volta() {
+        // volta 2 is missing, even without a repeat count
+        let why = play("|: A |1. B :| C").unwrap_err();
+        assert!(why.contains("volta no. 2 is not found"), "{}", why);
+        assert!(why.contains("in the repeat that ends on line 5"), "{}", why);
+        assert!(play("|: A |1. B |3. C :|").unwrap_err().contains("volta no. 2 is not found"));
+        // checked as soon as the volta ends
+        assert!(play("|: A |1. B :| |3. C | D").unwrap_err().contains("volta no. 2 is not found"));
+    }
+
+    #[test]
+    fn unfinished() {
+        assert!(play("|: A").unwrap_err().contains("repeat does not end"));
+        assert!(play("|: A |1. B :| |2. C").unwrap_err().contains("repeat does not end"));
+    }
+}
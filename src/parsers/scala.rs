@@ -0,0 +1,141 @@
+use crate::stores::tuning::{Keyboard, System};
+
+const COMMENT: char = '!';
+const UNMAPPED: &str = "x";
+
+/// read a file as string
+fn read(path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path).map_err(|why| format!("cannot read {}: {}", path, why))
+}
+
+/// lines of a scala file that are not comments
+fn lines(text: &str) -> impl Iterator<Item=&str> {
+    text.lines().filter(|line| !line.starts_with(COMMENT)).map(|line| line.trim())
+}
+
+/// first token of a line, the rest is ignored
+fn first(line: Option<&str>, what: &str) -> Result<String, String> {
+    line.and_then(|line| line.split_ascii_whitespace().next()).map(|token| token.to_string())
+        .ok_or_else(|| format!("missing {} in scala file", what))
+}
+
+/// parse a pitch of a scale as ratio, cents if it has a dot (e.g. `701.955`) or ratio otherwise (e.g. `3/2`, `2`)
+fn parse_pitch(token: &str) -> Result<f64, String> {
+    let invalid = || format!("invalid pitch in scala file: {}", token);
+    let ratio = if token.contains('.') {
+        2f64.powf(token.parse::<f64>().map_err(|_| invalid())? / 1200.0)
+    } else {
+        let (numerator, denominator) = token.split_once('/').unwrap_or((token, "1"));
+        match (numerator.parse::<u64>(), denominator.parse::<u64>()) {
+            (Ok(n), Ok(d)) if d != 0 => n as f64 / d as f64,
+            _ => return Err(invalid()),
+        }
+    };
+    if ratio > 0.0 { Ok(ratio) } else { Err(invalid()) }
+}
+
+/// parse the content of a `.scl` file
+/// returns the ratio of each degree, the last one being the period
+pub fn parse_scl(text: &str) -> Result<Vec<f64>, String> {
+    let mut lines = lines(text);
+    // the description can be anything, even empty
+    lines.next().ok_or("missing description in scala file")?;
+    let count = first(lines.next(), "number of notes")?;
+    let count = count.parse::<usize>().map_err(|_| format!("invalid number of notes in scala file: {}", count))?;
+    let ratios = (0..count).map(|_| parse_pitch(&first(lines.next(), "pitch")?)).collect::<Result<Vec<f64>, String>>()?;
+    match ratios.last() {
+        Some(&period) if period > 1.0 => Ok(ratios),
+        _ => Err("scala scale should end with a period greater than 1/1".to_string()),
+    }
+}
+
+/// parse the content of a `.kbm` file
+/// returns the keyboard mapping and the reference frequency
+pub fn parse_kbm(text: &str) -> Result<(Keyboard, f64), String> {
+    let mut lines = lines(text);
+    let number = |line: Option<&str>, what: &str| -> Result<i32, String> {
+        let token = first(line, what)?;
+        token.parse::<i32>().map_err(|_| format!("invalid {} in keyboard mapping: {}", what, token))
+    };
+    let size = number(lines.next(), "map size")?.max(0);
+    let range = (number(lines.next(), "first key")?, number(lines.next(), "last key")?);
+    let middle = number(lines.next(), "middle key")?;
+    let reference = number(lines.next(), "reference key")?;
+    let frequency = first(lines.next(), "reference frequency")?;
+    let frequency = frequency.parse::<f64>().ok().filter(|&f| f > 0.0).ok_or_else(
+        || format!("invalid reference frequency in keyboard mapping: {}", frequency)
+    )?;
+    let octave = number(lines.next(), "octave degree")?;
+    // missing entries at the end are unmapped
+    let map = (0..size).map(|_| match lines.next().and_then(|line| line.split_ascii_whitespace().next()) {
+        None | Some(UNMAPPED) => Ok(None),
+        Some(token) => token.parse::<i32>().map(Some).map_err(|_| format!("invalid degree in keyboard mapping: {}", token)),
+    }).collect::<Result<Vec<Option<i32>>, String>>()?;
+    Ok((Keyboard { map, range, middle, reference, octave }, frequency))
+}
+
+/// read a `.scl` file and an optional `.kbm` file
+/// returns the tuning system and the reference frequency of the keyboard mapping (if any)
+pub fn load(scl: &str, kbm: Option<&str>) -> Result<(System, Option<f64>), String> {
+    let ratios = parse_scl(&read(scl)?)?;
+    match kbm {
+        Some(kbm) => {
+            let (keyboard, reference) = parse_kbm(&read(kbm)?)?;
+            Ok((System::Scala(ratios, keyboard), Some(reference)))
+        }
+        None => Ok((System::Scala(ratios, Keyboard::default()), None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn pitches() {
+        assert_close(parse_pitch("3/2").unwrap(), 1.5);
+        assert_close(parse_pitch("2").unwrap(), 2.0);
+        assert_close(parse_pitch("1200.0").unwrap(), 2.0);
+        assert_close(parse_pitch("-100.").unwrap(), 2f64.powf(-1.0 / 12.0));
+        assert!(parse_pitch("3/0").is_err());
+        assert!(parse_pitch("0/1").is_err());
+        assert!(parse_pitch("-3/2").is_err());
+        assert!(parse_pitch("cents").is_err());
+    }
+
+    #[test]
+    fn scl() {
+        let text = "! meantone.scl\n!\nquarter-comma meantone, 3 degrees\n 3\n!\n 5/4 major third\n 696.578\n2/1\n";
+        let ratios = parse_scl(text).unwrap();
+        assert_eq!(ratios.len(), 3);
+        assert_close(ratios[0], 1.25);
+        assert_close(ratios[1], 2f64.powf(696.578 / 1200.0));
+        assert_close(ratios[2], 2.0);
+        // the description can be empty
+        assert_eq!(parse_scl("\n1\n2/1\n"), Ok(vec![2.0]));
+        assert!(parse_scl("missing\n3\n5/4\n3/2\n").unwrap_err().contains("missing pitch"));
+        assert!(parse_scl("no period\n1\n1/1\n").unwrap_err().contains("period"));
+        assert!(parse_scl("bad count\nthree\n").unwrap_err().contains("number of notes"));
+    }
+
+    #[test]
+    fn kbm() {
+        let text = "! C and G only\n12\n48\n84\n60\n60\n256.0\n2\n0\nx\nx\nx\nx\nx\nx\n1\n";
+        let (keyboard, frequency) = parse_kbm(text).unwrap();
+        assert_close(frequency, 256.0);
+        assert_eq!(keyboard, Keyboard {
+            // missing entries at the end are unmapped
+            map: vec![Some(0), None, None, None, None, None, None, Some(1), None, None, None, None],
+            range: (48, 84),
+            middle: 60,
+            reference: 60,
+            octave: 2,
+        });
+        assert!(parse_kbm("0\n0\n127\n60\n69\n-440\n0\n").unwrap_err().contains("reference frequency"));
+        assert!(parse_kbm("1\n0\n127\n60\n69\n440\n0\ny\n").unwrap_err().contains("invalid degree"));
+    }
+}
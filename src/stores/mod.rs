@@ -1,3 +1,5 @@
 //! structs that stores data
 pub mod waveform;
 pub mod note;
+pub mod timbre;
+pub mod tuning;
@@ -2,16 +2,151 @@ use std::ops::Add;
 use std::rc::Rc;
 use std::slice::Iter;
 
+use crate::stores::timbre::Timbre;
+
+//#region Pitch
+/// ornament that expands a note into a sequence of notes
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Ornament {
+    /// alternate principal and upper until the end
+    Trill,
+    /// principal, upper, then principal until the end
+    UpperMordent,
+    /// principal, lower, then principal until the end
+    LowerMordent,
+    /// upper, principal, lower, then principal until the end
+    Turn,
+}
+
+/// short note that takes its time from another chord
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Grace {
+    /// on the beat, takes half of the time of its chord
+    Appoggiatura,
+    /// before the beat, takes a short time from the preceding chord
+    Acciaccatura,
+}
+
+/// a note in a chord
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Pitch {
+    /// Hz, 0.0 for rest
+    pub frequency: f64,
+    /// ornament and the frequencies of its upper and lower neighbours
+    pub ornament: Option<(Ornament, f64, f64)>,
+    /// number of ornament notes per second
+    pub speed: f64,
+    /// if the note is a grace note
+    pub grace: Option<Grace>,
+    /// Hz reached at the end of the note (glissando)
+    pub glide: Option<f64>,
+    /// key number (MIDI) and cents, unless the frequency is given in Hz
+    pub key: Option<(i32, i32)>,
+}
+
+impl Pitch {
+    pub fn new(frequency: f64) -> Self {
+        Self { frequency, ornament: None, speed: 0.0, grace: None, glide: None, key: None }
+    }
+    /// scale all frequencies, return new Self
+    pub fn scale(&self, scale: f64) -> Self {
+        Self {
+            frequency: self.frequency * scale,
+            ornament: self.ornament.map(|(o, upper, lower)| (o, upper * scale, lower * scale)),
+            glide: self.glide.map(|glide| glide * scale),
+            ..*self
+        }
+    }
+    /// Hz at the end of the note
+    pub fn last(&self) -> f64 {
+        self.glide.unwrap_or(self.frequency)
+    }
+    /// frequency at frame `i` of a note of `len` frames, after the ornament is expanded
+    pub fn at(&self, i: usize, len: usize, fps: u32) -> f64 {
+        match self.ornament {
+            // slide by the same interval every frame, until the end of the note
+            None => match self.glide {
+                Some(glide) => self.frequency * (glide / self.frequency).powf((i as f64 / len as f64).min(1.0)),
+                None => self.frequency,
+            }
+            Some((ornament, upper, lower)) => {
+                // index of the ornament note
+                let n = (i as f64 * self.speed / fps as f64) as usize;
+                match (ornament, n) {
+                    (Ornament::Trill, n) if n % 2 == 1 => upper,
+                    (Ornament::UpperMordent, 1) => upper,
+                    (Ornament::LowerMordent, 1) => lower,
+                    (Ornament::Turn, 0) => upper,
+                    (Ornament::Turn, 2) => lower,
+                    _ => self.frequency,
+                }
+            }
+        }
+    }
+}
+//#endregion Pitch
+
+//#region Articulation
+/// position of a chord in a slur
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Slur {
+    /// attacked but not released
+    Start,
+    /// neither attacked nor released
+    Middle,
+    /// released but not attacked
+    End,
+}
+
+/// how the notes of a chord are attacked and held
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct Articulation {
+    /// louder
+    pub accent: bool,
+    /// held for the full value, legato
+    pub tenuto: bool,
+    /// even louder than accent
+    pub marcato: bool,
+    /// held longer than its value
+    pub fermata: bool,
+    /// legato with the chords before and/or after
+    pub slur: Option<Slur>,
+}
+
+impl Articulation {
+    /// amplitude of the loudest articulation (marcato), kept as headroom for every note
+    pub const LOUDEST: f64 = 1.8;
+    /// amplitude relative to an unarticulated note
+    pub fn gain(&self) -> f64 {
+        if self.marcato {
+            Self::LOUDEST
+        } else if self.accent {
+            1.4
+        } else {
+            1.0
+        }
+    }
+}
+//#endregion Articulation
+
 //#region Chord
 #[derive(Clone, Debug)]
 pub struct Chord {
     /// length of the notes
     pub length: usize,
-    /// frequency of each note
-    pub frequencies: Vec<f64>,
+    /// pitch of each note
+    pub pitches: Vec<Pitch>,
     // made this field only for you, staccato
     /// duration that the notes occupies
     pub size: usize,
+    /// stereo position, from -1.0 (left) to 1.0 (right)
+    pub pan: f64,
+    /// how the notes sound
+    pub timbre: Rc<Timbre>,
+    /// accent, tenuto, marcato, fermata
+    pub articulation: Articulation,
+    /// number of frames between the start of the line and the start of the notes
+    pub delay: usize,
 }
 
 impl Chord {
@@ -19,15 +154,11 @@ impl Chord {
         Self {
             length: 0,
             size: 0,
-            frequencies: Vec::new(),
-        }
-    }
-    /// scale all frequencies, return new Self
-    pub fn scale(&self, scale: f64) -> Self {
-        Self {
-            length: self.length,
-            size: self.size,
-            frequencies: self.frequencies.iter().map(|&f| f * scale).collect(),
+            pitches: Vec::new(),
+            pan: 0.0,
+            timbre: Rc::default(),
+            articulation: Articulation::default(),
+            delay: 0,
         }
     }
     /// returns `true` if `self` can be replaced by chord
@@ -36,45 +167,53 @@ impl Chord {
             && (self.size == chord.size && self.length == chord.length)
             || (self.size == 0 && self.length == 0)
     }
-    /// returns `true` if `self` has no frequencies
+    /// returns `true` if `self` has no pitches
     pub fn is_empty(&self) -> bool {
-        self.frequencies.is_empty()
+        self.pitches.is_empty()
     }
     /// returns number of notes `self` has
     pub fn count(&self) -> usize {
-        self.frequencies.len()
+        self.pitches.len()
     }
-    /// push a new frequency to chord
-    pub fn push(&mut self, frequency: f64) {
-        self.frequencies.push(frequency);
+    /// push a new pitch to chord
+    pub fn push(&mut self, pitch: Pitch) {
+        self.pitches.push(pitch);
         // f64 does not implement Eq ffs
         // assert!(self.frequencies.insert(frequency), "attempt to insert existing frequency to a chord: {}", frequency);
     }
-    /// extend the frequencies in rhs to lhs
+    /// extend the pitches in rhs to lhs
     pub fn extend(&mut self, rhs: &Rc<Chord>) {
-        self.frequencies.extend(rhs.frequencies.iter());
+        self.pitches.extend(rhs.pitches.iter());
+    }
+    /// remove and return the grace notes
+    pub fn take_graces(&mut self) -> Vec<Pitch> {
+        let (graces, pitches) = self.pitches.iter().partition(|p| p.grace.is_some());
+        self.pitches = pitches;
+        graces
     }
 }
 
 impl Add for Chord {
     type Output = Self;
 
-    /// create new chord with length and size from lhs and frequencies from both
+    /// create new chord with length and size from lhs and pitches from both
     /// (thus the operation is not commutative)
     fn add(self, rhs: Self) -> Self::Output {
         if rhs.is_empty() {
             Self { ..self }
         } else {
-            let mut frequencies = self.frequencies.clone();
-            frequencies.extend(&rhs.frequencies);
-            Self { frequencies, ..self }
+            let mut pitches = self.pitches.clone();
+            pitches.extend(&rhs.pitches);
+            Self { pitches, ..self }
         }
     }
 }
 
 impl PartialEq for Chord {
     fn eq(&self, other: &Self) -> bool {
-        self.size == other.size && self.length == other.length && self.frequencies == other.frequencies
+        self.size == other.size && self.length == other.length && self.pitches == other.pitches
+            && self.pan == other.pan && self.timbre == other.timbre && self.articulation == other.articulation
+            && self.delay == other.delay
     }
 }
 //#endregion  Chord
@@ -84,11 +223,15 @@ impl PartialEq for Chord {
 #[derive(Clone, Debug)]
 pub struct Line {
     chords: Vec<Rc<Chord>>,
+    /// chords of grace notes, not counted in offset and size
+    graces: Vec<Rc<Chord>>,
+    /// level at the start and at the end (offset) of the line
+    gain: (f64, f64),
 }
 
 impl Line {
     pub fn new() -> Self {
-        Self { chords: Vec::new() }
+        Self { chords: Vec::new(), graces: Vec::new(), gain: (1.0, 1.0) }
     }
     /// defined as the minimum size of each chord
     pub fn offset(&self) -> usize {
@@ -101,8 +244,36 @@ impl Line {
     pub fn push(&mut self, chord: Rc<Chord>) {
         self.chords.push(chord);
     }
-    pub fn chords(&self) -> Iter<Rc<Chord>> {
+    /// returns number of chords `self` has
+    pub fn count(&self) -> usize {
+        self.chords.len()
+    }
+    pub fn chords(&self) -> Iter<'_, Rc<Chord>> {
         self.chords.iter()
     }
+    /// i-th chord of the line
+    pub fn get(&self, i: usize) -> Option<&Rc<Chord>> {
+        self.chords.get(i)
+    }
+    /// replace the i-th chord of the line
+    pub fn replace(&mut self, i: usize, chord: Rc<Chord>) {
+        self.chords[i] = chord;
+    }
+    pub fn push_grace(&mut self, chord: Rc<Chord>) {
+        self.graces.push(chord);
+    }
+    pub fn graces(&self) -> Iter<'_, Rc<Chord>> {
+        self.graces.iter()
+    }
+    /// change the level at the start and at the end of the line
+    pub fn set_gain(&mut self, start: f64, end: f64) {
+        self.gain = (start, end);
+    }
+    /// level at frame `i`, ramps linearly until the offset
+    pub fn gain(&self, i: usize) -> f64 {
+        let (start, end) = self.gain;
+        let offset = self.offset();
+        if i >= offset { end } else { start + (end - start) * i as f64 / offset as f64 }
+    }
 }
 //#endregion Line
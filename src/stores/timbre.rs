@@ -0,0 +1,161 @@
+use std::f64::consts::PI;
+
+use crate::stores::note::Slur;
+
+/// shape of a single period of the wave
+#[derive(Clone, PartialEq, Debug)]
+pub enum Oscillator {
+    Sine,
+    Square,
+    Saw,
+    Triangle,
+    /// (ratio of the period that is high, from 0.0 to 1.0)
+    Pulse(f64),
+    /// white noise, ignores the frequency
+    Noise,
+    /// (amplitude of each harmonic, starting from the fundamental)
+    Harmonics(Vec<f64>),
+}
+
+/// make sine shape
+fn sinusoid(x: f64) -> f64 { ((x * PI).cos() + 1.0) / 2.0 }
+
+/// deterministic white noise from -1.0 to 1.0 (splitmix64)
+fn noise(seed: u64) -> f64 {
+    let mut z = seed.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+}
+
+impl Oscillator {
+    /// amplitude from -1.0 to 1.0 at `phase` (number of periods since the start)
+    /// `seed` is only used by noise
+    pub fn sample(&self, phase: f64, seed: u64) -> f64 {
+        // position within the current period
+        let x = phase.fract();
+        match self {
+            Self::Sine => (2.0 * PI * phase).sin(),
+            Self::Square => if x < 0.5 { 1.0 } else { -1.0 },
+            Self::Saw => 2.0 * x - 1.0,
+            Self::Triangle => 1.0 - 4.0 * (x - 0.5).abs(),
+            Self::Pulse(width) => if x < *width { 1.0 } else { -1.0 },
+            Self::Noise => noise(seed),
+            Self::Harmonics(amps) => {
+                // normalise so that the peak never exceeds 1.0
+                let sum: f64 = amps.iter().map(|a| a.abs()).sum();
+                amps.iter().enumerate().map(
+                    |(i, a)| a * (2.0 * PI * phase * (i + 1) as f64).sin()
+                ).sum::<f64>() / if sum == 0.0 { 1.0 } else { sum }
+            }
+        }
+    }
+}
+
+/// amplitude of a note over time
+#[derive(Clone, PartialEq, Debug)]
+pub enum Envelope {
+    /// raised cosine from 1.0 down to 0.0 over the length of the note
+    Sinusoid,
+    /// attack, decay and release in seconds, sustain as level from 0.0 to 1.0
+    Adsr { attack: f64, decay: f64, sustain: f64, release: f64 },
+}
+
+impl Envelope {
+    /// the same envelope held at full level until the end of the note (tenuto)
+    pub fn legato(&self) -> Self {
+        match self {
+            // hold and fade out quickly after the note, overlapping with the next one
+            Self::Sinusoid => Self::Adsr { attack: 0.005, decay: 0.0, sustain: 1.0, release: 0.05 },
+            Self::Adsr { attack, release, .. } => Self::Adsr {
+                attack: *attack,
+                decay: 0.0,
+                sustain: 1.0,
+                release: *release,
+            },
+        }
+    }
+    /// the legato envelope cut at the start and/or at the end, to connect with other notes of a slur
+    pub fn slurred(&self, slur: Slur) -> Self {
+        match (self.legato(), slur) {
+            (Self::Adsr { attack, .. }, Slur::Start) => Self::Adsr { attack, decay: 0.0, sustain: 1.0, release: 0.0 },
+            (Self::Adsr { release, .. }, Slur::End) => Self::Adsr { attack: 0.0, decay: 0.0, sustain: 1.0, release },
+            _ => Self::Adsr { attack: 0.0, decay: 0.0, sustain: 1.0, release: 0.0 },
+        }
+    }
+    /// number of frames the note keeps ringing after its length
+    pub fn ring(&self, fps: u32) -> usize {
+        match self {
+            Self::Sinusoid => 0,
+            Self::Adsr { release, .. } => (release * fps as f64) as usize,
+        }
+    }
+    /// amplitude from 0.0 to 1.0 at frame `i` of a note of `len` frames
+    pub fn level(&self, i: usize, len: usize, fps: u32) -> f64 {
+        match self {
+            Self::Sinusoid => if i < len { sinusoid(i as f64 / len as f64) } else { 0.0 },
+            Self::Adsr { attack, decay, sustain, release } => {
+                // level while the note is held
+                let held = |t: f64| if t < *attack {
+                    t / attack
+                } else if t < attack + decay {
+                    1.0 - (1.0 - sustain) * (t - attack) / decay
+                } else {
+                    *sustain
+                };
+                let t = i as f64 / fps as f64;
+                if i < len {
+                    held(t)
+                } else {
+                    // fade out from wherever the note was released
+                    let off = len as f64 / fps as f64;
+                    held(off) * (1.0 - (t - off) / release).max(0.0)
+                }
+            }
+        }
+    }
+}
+
+/// how a note sounds, regardless of its pitch and length
+#[derive(Clone, PartialEq, Debug)]
+pub struct Timbre {
+    pub oscillator: Oscillator,
+    pub envelope: Envelope,
+    /// seconds to glide from the previous note in the same position, 0.0 for none
+    pub portamento: f64,
+    /// periodic change of pitch (rate in Hz, depth in cents)
+    pub vibrato: Option<(f64, f64)>,
+    /// periodic change of amplitude (rate in Hz, depth from 0.0 to 1.0)
+    pub tremolo: Option<(f64, f64)>,
+}
+
+impl Timbre {
+    /// ratio of the frequency at `t` seconds into the note, from vibrato
+    pub fn bend(&self, t: f64) -> f64 {
+        match self.vibrato {
+            Some((rate, cents)) => 2f64.powf(cents / 1200.0 * (2.0 * PI * rate * t).sin()),
+            None => 1.0,
+        }
+    }
+    /// ratio of the amplitude at `t` seconds into the note, from tremolo
+    pub fn swell(&self, t: f64) -> f64 {
+        match self.tremolo {
+            // start at full level, dip down to 1.0 - depth
+            Some((rate, depth)) => 1.0 - depth * (1.0 - sinusoid(2.0 * rate * t)),
+            None => 1.0,
+        }
+    }
+}
+
+impl Default for Timbre {
+    fn default() -> Self {
+        Self {
+            oscillator: Oscillator::Sine,
+            envelope: Envelope::Sinusoid,
+            portamento: 0.0,
+            vibrato: None,
+            tremolo: None,
+        }
+    }
+}
@@ -0,0 +1,200 @@
+/// MIDI note number of A4
+const A4: i32 = 69;
+/// 5-limit just intonation, ratio of each semitone above the tonic
+const JUST: [f64; 12] = [
+    1.0, 16.0 / 15.0, 9.0 / 8.0, 6.0 / 5.0, 5.0 / 4.0, 4.0 / 3.0,
+    45.0 / 32.0, 3.0 / 2.0, 8.0 / 5.0, 5.0 / 3.0, 9.0 / 5.0, 15.0 / 8.0,
+];
+
+/// ratio of each semitone above the tonic, tuned as a chain of 12 `fifth`s starting `lowest` fifths below the tonic
+fn chain(fifth: f64, lowest: i32) -> [f64; 12] {
+    let mut ratios = [1.0; 12];
+    for f in lowest..lowest + 12 {
+        let ratio = fifth.powi(f);
+        // back into the octave above the tonic
+        ratios[(7 * f).rem_euclid(12) as usize] = ratio / 2f64.powf(ratio.log2().floor());
+    }
+    ratios
+}
+
+/// which key plays which degree of a scala scale (`.kbm`)
+#[derive(Clone, PartialEq, Debug)]
+pub struct Keyboard {
+    /// degree of each key in a period of the mapping, None if unmapped, empty for one degree per key
+    pub map: Vec<Option<i32>>,
+    /// first and last mapped key (MIDI)
+    pub range: (i32, i32),
+    /// key of degree 0 (MIDI)
+    pub middle: i32,
+    /// key of the reference frequency (MIDI)
+    pub reference: i32,
+    /// number of degrees the mapping moves by each period, 0 for the size of the scale
+    pub octave: i32,
+}
+
+impl Default for Keyboard {
+    fn default() -> Self {
+        Self { map: Vec::new(), range: (0, 127), middle: 60, reference: A4, octave: 0 }
+    }
+}
+
+/// how the frequency of each key is derived
+#[derive(Clone, PartialEq, Debug)]
+pub enum System {
+    /// 12 tone equal temperament
+    Equal,
+    /// 5-limit just intonation (semitones of the tonic above C)
+    Just(i32),
+    /// chain of pure fifths (semitones of the tonic above C)
+    Pythagorean(i32),
+    /// quarter-comma meantone (semitones of the tonic above C)
+    Meantone(i32),
+    /// (ratio of each degree of a scala scale, the last one being the period, and its keyboard mapping)
+    Scala(Vec<f64>, Keyboard),
+}
+
+/// tuning system and the frequency of its reference key
+#[derive(Clone, PartialEq, Debug)]
+pub struct Tuning {
+    /// Hz of the reference key (A4 unless a keyboard mapping says otherwise)
+    pub reference: f64,
+    pub system: System,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self { reference: 440.0, system: System::Equal }
+    }
+}
+
+impl Tuning {
+    /// Hz of a key (MIDI note number)
+    pub fn frequency(&self, key: i32) -> Result<f64, String> {
+        let reference = match &self.system {
+            System::Scala(_, keyboard) => keyboard.reference,
+            _ => A4,
+        };
+        Ok(self.reference * self.ratio(key)? / self.ratio(reference)?)
+    }
+    /// ratio of a key to some fixed key, only meaningful when divided by another ratio
+    fn ratio(&self, key: i32) -> Result<f64, String> {
+        // ratio of a tonic-relative system, given the ratio of each semitone in an octave
+        let relative = |ratios: [f64; 12], tonic: i32| {
+            let n = key - tonic;
+            ratios[n.rem_euclid(12) as usize] * 2f64.powi(n.div_euclid(12))
+        };
+        Ok(match &self.system {
+            System::Equal => 2f64.powf(key as f64 / 12.0),
+            System::Just(tonic) => relative(JUST, *tonic),
+            System::Pythagorean(tonic) => relative(chain(1.5, -5), *tonic),
+            System::Meantone(tonic) => relative(chain(5f64.powf(0.25), -3), *tonic),
+            System::Scala(ratios, keyboard) => {
+                let Keyboard { map, range, middle, octave, .. } = keyboard;
+                if key < range.0 || key > range.1 {
+                    return Err(format!("key {} is out of the range of the keyboard mapping", key));
+                }
+                let size = ratios.len() as i32;
+                let i = key - middle;
+                let degree = if map.is_empty() {
+                    i
+                } else {
+                    let octave = if *octave == 0 { size } else { *octave };
+                    let m = map.len() as i32;
+                    map[i.rem_euclid(m) as usize].ok_or_else(
+                        || format!("key {} is not mapped by the keyboard mapping", key)
+                    )? + i.div_euclid(m) * octave
+                };
+                let r = degree.rem_euclid(size);
+                ratios[ratios.len() - 1].powi(degree.div_euclid(size)) * if r == 0 { 1.0 } else { ratios[r as usize - 1] }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const C4: i32 = 60;
+    const E4: i32 = 64;
+    const G4: i32 = 67;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} is not {}", actual, expected);
+    }
+
+    fn tuning(system: System) -> Tuning {
+        Tuning { system, ..Tuning::default() }
+    }
+
+    /// ratio between the frequencies of two keys
+    fn interval(tuning: &Tuning, from: i32, to: i32) -> f64 {
+        tuning.frequency(to).unwrap() / tuning.frequency(from).unwrap()
+    }
+
+    #[test]
+    fn equal() {
+        let equal = Tuning::default();
+        assert_close(equal.frequency(A4).unwrap(), 440.0);
+        assert_close(equal.frequency(A4 + 12).unwrap(), 880.0);
+        assert_close(equal.frequency(C4).unwrap(), 440.0 * 2f64.powf(-9.0 / 12.0));
+        let baroque = Tuning { reference: 415.0, ..Tuning::default() };
+        assert_close(baroque.frequency(A4).unwrap(), 415.0);
+    }
+
+    #[test]
+    fn reference_keeps_its_frequency() {
+        for system in [System::Just(0), System::Pythagorean(7), System::Meantone(3)] {
+            assert_close(tuning(system).frequency(A4).unwrap(), 440.0);
+        }
+    }
+
+    #[test]
+    fn just() {
+        let just = tuning(System::Just(0));
+        assert_close(interval(&just, C4, E4), 5.0 / 4.0);
+        assert_close(interval(&just, C4, G4), 3.0 / 2.0);
+        assert_close(interval(&just, C4, C4 + 12), 2.0);
+        // relative to the tonic, D to F#
+        assert_close(interval(&tuning(System::Just(2)), C4 + 2, E4 + 2), 5.0 / 4.0);
+    }
+
+    #[test]
+    fn pythagorean() {
+        let pythagorean = tuning(System::Pythagorean(0));
+        assert_close(interval(&pythagorean, C4, G4), 3.0 / 2.0);
+        assert_close(interval(&pythagorean, C4, E4), 81.0 / 64.0);
+        // the wolf is between the last and the first fifth of the chain, F# to Db
+        assert_close(interval(&pythagorean, C4 - 6, C4 + 1), 262144.0 / 177147.0);
+    }
+
+    #[test]
+    fn meantone() {
+        let meantone = tuning(System::Meantone(0));
+        assert_close(interval(&meantone, C4, E4), 5.0 / 4.0);
+        assert_close(interval(&meantone, C4, G4), 5f64.powf(0.25));
+    }
+
+    #[test]
+    fn scala() {
+        // 12 equal degrees mapped one per key is equal temperament
+        let ratios = (1..=12).map(|i| 2f64.powf(i as f64 / 12.0)).collect();
+        let scala = tuning(System::Scala(ratios, Keyboard::default()));
+        for key in [0, C4, A4, 127] {
+            assert_close(scala.frequency(key).unwrap(), Tuning::default().frequency(key).unwrap());
+        }
+        // 2 degrees of 3/2 and 2, mapped to C and G of each octave only
+        let keyboard = Keyboard {
+            map: vec![Some(0), None, None, None, None, None, None, Some(1), None, None, None, None],
+            range: (48, 84),
+            reference: C4,
+            ..Keyboard::default()
+        };
+        let scala = Tuning { reference: 256.0, system: System::Scala(vec![1.5, 2.0], keyboard) };
+        assert_close(scala.frequency(G4).unwrap(), 384.0);
+        assert_close(scala.frequency(C4 + 12).unwrap(), 512.0);
+        assert_close(scala.frequency(G4 - 12).unwrap(), 192.0);
+        assert!(scala.frequency(E4).unwrap_err().contains("not mapped"));
+        assert!(scala.frequency(A4 + 24).unwrap_err().contains("out of the range"));
+    }
+}
@@ -1,13 +1,15 @@
-use std::f64::consts::PI;
+use std::f64::consts::FRAC_PI_4;
 
-use crate::stores::note::Line;
+use crate::stores::note::{Articulation, Chord, Line, Pitch, Slur};
+use crate::stores::timbre::Timbre;
 
-/// make sine shape
-fn sinusoid(x: f64) -> f64 { ((x * PI).cos() + 1.0) / 2.0 }
+/// number of interleaved channels (left, right)
+pub const CHANNELS: u16 = 2;
 
-/// create waveform
-fn sine(i: f64, n: f64, period: f64, curve: &dyn Fn(f64) -> f64) -> f64 {
-    curve(i / n) * (period * i).sin()
+/// constant power gains of left and right channel given a pan from -1.0 to 1.0
+fn gains(pan: f64) -> (f64, f64) {
+    let angle = (pan + 1.0) * FRAC_PI_4;
+    (angle.cos(), angle.sin())
 }
 
 pub struct Waveform {
@@ -17,25 +19,33 @@ pub struct Waveform {
     pub max: usize,
     /// number of samples/frames per second
     pub fps: u32,
-    /// waveform buffer
-    buffer: Vec<i16>,
+    /// waveform buffer, interleaved left and right samples from -1.0 to 1.0
+    buffer: Vec<f64>,
+    /// phase and Hz at the end of each note of each chord position,
+    /// for the next chord of a slur to continue from, and for portamento to glide from
+    ends: Vec<Vec<(f64, f64)>>,
 }
 
 impl Waveform {
     pub fn new(max: usize, fps: u32) -> Self {
-        Self { max, bpm: 0, fps, buffer: Vec::new() }
+        Self { max, bpm: 0, fps, buffer: Vec::new(), ends: Vec::new() }
     }
     /// return number of frames given the length as beat
     pub fn frame_count(&self, beat: f64) -> usize {
         assert_ne!(self.bpm, 0, "BPM is at 0.0 while trying to get frame count");
         //      duration in seconds     ) number of frames )
-        ((beat * 240.0 / self.bpm as f64) * self.fps as f64) as usize
+        ((beat * 240.0 / self.bpm as f64) * self.fps as f64).round() as usize
     }
     //#region fold buffer
-    /// add a note onto the waveform
-    fn fold_with_note(&mut self, len: usize, freq: f64, max: usize) -> Result<(), String> {
+    /// add a note onto the waveform, starting at `phase` and gliding from `from` Hz (if portamento)
+    /// returns the phase and Hz at the end of its length
+    fn fold_with_note(
+        &mut self, line: &Line, chord: &Chord, pitch: &Pitch, max: usize, (mut phase, from): (f64, f64),
+    ) -> Result<(f64, f64), String> {
+        let len = chord.length;
+        let freq = pitch.frequency;
         // no need to add rests
-        if freq == 0.0 { return Ok(()); }
+        if freq == 0.0 { return Ok((phase, 0.0)); }
         if len == 0 || self.bpm == 0 {
             match (len, self.bpm) {
                 (0, 0) => Err(format!("frame count and BPM are 0.0 at {:.2} Hz", freq)),
@@ -44,40 +54,92 @@ impl Waveform {
                 _ => panic!("wot"),
             }
         } else {
-            let period = freq * PI * 2.0 / self.fps as f64;
-            let amp = i16::MAX as f64 / self.max.max(max) as f64;
+            let fps = self.fps;
+            // every note leaves room for the loudest articulation, so accents stay louder in a full chord
+            let amp = chord.articulation.gain() / (self.max.max(max) as f64 * Articulation::LOUDEST);
+            let (left, right) = gains(chord.pan);
+            let timbre = &*chord.timbre;
+            let Timbre { oscillator, envelope, portamento, .. } = timbre;
+            // number of frames to glide from the previous note, none after a rest
+            let glide = if from == 0.0 { 0 } else { (portamento * fps as f64) as usize };
+            let envelope = match (chord.articulation.slur, chord.articulation.tenuto) {
+                (Some(slur), _) => envelope.slurred(slur),
+                (None, true) => envelope.legato(),
+                (None, false) => envelope.clone(),
+            };
+            // the note may ring past its length (release)
+            let frames = len + envelope.ring(fps);
+            let delay = chord.delay;
+            if self.buffer.len() < (delay + frames) * CHANNELS as usize {
+                self.buffer.resize((delay + frames) * CHANNELS as usize, 0.0);
+            }
             // add new wave to buffer
-            Ok((0..len).map(
-                |i| amp * sine(i as f64, len as f64, period, &sinusoid)
-            ).enumerate().for_each(|(i, y)| self.buffer[i] += y as i16))
+            // (accumulate the phase so that the wave stays continuous when the frequency changes)
+            let mut end = phase;
+            (0..frames).map(|i| {
+                if i == len { end = phase; }
+                let t = i as f64 / fps as f64;
+                let y = amp * line.gain(delay + i) * envelope.level(i, len, fps) * timbre.swell(t)
+                    * oscillator.sample(phase, freq.to_bits().wrapping_add(i as u64));
+                // the interval to the previous note shrinks to nothing over the glide
+                let bend = if i < glide { (from / freq).powf(1.0 - i as f64 / glide as f64) } else { 1.0 };
+                phase += bend * timbre.bend(t) * pitch.at(i, len, fps) / fps as f64;
+                y
+            }).zip(self.buffer.chunks_exact_mut(CHANNELS as usize).skip(delay)).for_each(|(y, frame)| {
+                frame[0] += y * left;
+                frame[1] += y * right;
+            });
+            Ok((if frames == len { phase } else { end }, pitch.last()))
         }
     }
     // fold a new line into the accumulative buffer
     pub fn fold_with_line(&mut self, line: &Line) -> Result<(), String> {
         let size = line.size();
         if size == 0 {
-            Err(format!("line size is 0 while trying to add to waveform"))
+            Err("line size is 0 while trying to add to waveform".to_string())
         } else {
             // resize buffer if needed
-            if self.buffer.len() < size {
-                self.buffer.resize(size, 0);
+            let samples = size * CHANNELS as usize;
+            if self.buffer.len() < samples {
+                self.buffer.resize(samples, 0.0);
             }
             // maximum number of notes to be play at the same time
             let max = line.chords().fold(0, |acc, chord| if chord.count() > acc {
                 chord.count()
             } else { acc });
 
-            for chord in line.chords() {
-                let length = chord.length;
-                for freq in chord.frequencies.iter() {
-                    self.fold_with_note(length, *freq, max)?;
+            for (voice, chord) in line.chords().enumerate() {
+                if self.ends.len() <= voice {
+                    self.ends.resize(voice + 1, Vec::new());
+                }
+                let ends = std::mem::take(&mut self.ends[voice]);
+                let mut next = Vec::with_capacity(chord.pitches.len());
+                for (i, pitch) in chord.pitches.iter().enumerate() {
+                    let (phase, from) = ends.get(i).copied().unwrap_or((0.0, 0.0));
+                    // continue from the previous chord only in the middle of a slur
+                    let phase = match chord.articulation.slur {
+                        Some(Slur::Middle | Slur::End) => phase,
+                        _ => 0.0,
+                    };
+                    next.push(self.fold_with_note(line, chord, pitch, max, (phase, from))?);
+                }
+                self.ends[voice] = next;
+            }
+            for chord in line.graces() {
+                for pitch in chord.pitches.iter() {
+                    self.fold_with_note(line, chord, pitch, max, (0.0, 0.0))?;
                 }
             }
             Ok(())
         }
     }
     //#endregion write to buffer
-    pub fn drain(&mut self, end: usize) -> Vec<i16> {
-        self.buffer.drain(..end).collect()
+    /// drain the first `end` frames of all channels
+    pub fn drain(&mut self, end: usize) -> Vec<f64> {
+        self.buffer.drain(..end * CHANNELS as usize).collect()
+    }
+    /// drain everything left in the buffer (e.g. release of the last notes)
+    pub fn flush(&mut self) -> Vec<f64> {
+        self.buffer.drain(..).collect()
     }
-}
\ No newline at end of file
+}
@@ -1,59 +1,136 @@
 use std::fs::File;
 use std::io::{Result, Seek, SeekFrom, Write};
-use std::mem::{size_of, transmute};
-use std::slice::from_raw_parts;
+
+/// sample format of the output
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    /// integer PCM with given bits per sample (8, 16, 24 or 32)
+    Int(u16),
+    /// 32-bit IEEE float
+    Float,
+}
+
+impl Format {
+    /// parse format from command line, e.g. `16` or `32f`
+    pub fn parse(arg: &str) -> Option<Self> {
+        match arg {
+            "8" => Some(Self::Int(8)),
+            "16" => Some(Self::Int(16)),
+            "24" => Some(Self::Int(24)),
+            "32" => Some(Self::Int(32)),
+            "32f" => Some(Self::Float),
+            _ => None,
+        }
+    }
+    /// bits per sample
+    fn width(&self) -> u16 {
+        match self {
+            Self::Int(width) => *width,
+            Self::Float => 32,
+        }
+    }
+    /// format tag in fmt chunk
+    fn tag(&self) -> u16 {
+        match self {
+            Self::Int(_) => 1,
+            Self::Float => 3,
+        }
+    }
+    /// convert a sample to little endian bytes, integers are clipped to [-1, 1]
+    fn encode(&self, y: f64, bytes: &mut Vec<u8>) {
+        // float keeps everything out of range for the mixing tools
+        if let Self::Float = self {
+            return bytes.extend((y as f32).to_le_bytes());
+        }
+        let y = y.clamp(-1.0, 1.0);
+        match self {
+            // 8-bit PCM is unsigned
+            Self::Int(8) => bytes.push((y * i8::MAX as f64 + 128.0) as u8),
+            Self::Int(16) => bytes.extend(((y * i16::MAX as f64) as i16).to_le_bytes()),
+            Self::Int(24) => bytes.extend(&((y * 8388607.0) as i32).to_le_bytes()[..3]),
+            _ => bytes.extend(((y * i32::MAX as f64) as i32).to_le_bytes()),
+        }
+    }
+}
 
 pub struct Writer {
     /// output file (`.wav`)
     file: File,
+    /// sample format
+    format: Format,
+    /// bytes per frame of all channels
+    block_align: u16,
+    /// position of the sample frame count in fact chunk (float only)
+    fact: Option<u64>,
+    /// position of the data chunk size
+    data: u64,
 }
 
 impl Writer {
-    pub fn new(destination: File) -> Self {
-        Self { file: destination }
+    pub fn new(destination: File, format: Format) -> Self {
+        Self { file: destination, format, block_align: 0, fact: None, data: 0 }
     }
     /// write headers
-    pub fn start(&mut self, rate: u32) -> Result<()> {
-        let nch = 1u16;
-        let frame_width = 16u16;
-        self.file.write(&[
+    pub fn start(&mut self, rate: u32, nch: u16) -> Result<()> {
+        let frame_width = self.format.width();
+        self.block_align = nch * frame_width / 8;
+        // non-PCM formats need the cbSize field
+        let fmt_size: u32 = if self.format == Format::Float { 18 } else { 16 };
+        self.file.write_all(&[
             82, 73, 70, 70, // RIFF
             0, 0, 0, 0, // file size
             87, 65, 86, 69, // WAVE
             102, 109, 116, 32, // fmt
-            16, 0, 0, 0, // fmt chunk size
-            1, 0, // format tag (PCM)
         ])?;
-        self.file.write(&unsafe { transmute(nch) } as &[u8; 2])?;
+        self.file.write_all(&fmt_size.to_le_bytes())?;
+        // format tag (1 for PCM, 3 for IEEE float)
+        self.file.write_all(&self.format.tag().to_le_bytes())?;
+        self.file.write_all(&nch.to_le_bytes())?;
         // frame rate (fps)
-        self.file.write(&unsafe { transmute(rate) } as &[u8; 4])?;
+        self.file.write_all(&rate.to_le_bytes())?;
         // byte rate
-        self.file.write(&unsafe { transmute(rate * frame_width as u32) } as &[u8; 4])?;
+        self.file.write_all(&(rate * self.block_align as u32).to_le_bytes())?;
         // block align
-        self.file.write(&[2, 0])?;
+        self.file.write_all(&self.block_align.to_le_bytes())?;
         // bits per frame
-        self.file.write(&unsafe { transmute(frame_width) } as &[u8; 2])?;
+        self.file.write_all(&frame_width.to_le_bytes())?;
 
-        self.file.write(&[
-            100, 97, 116, 97, // data
-            0, 0, 0, 0, // nframes * nchannels * bytes / frame, also is file size - 36
-        ])?;
+        if self.format == Format::Float {
+            // cbSize (no extension)
+            self.file.write_all(&[0, 0])?;
+            self.file.write_all(&[
+                102, 97, 99, 116, // fact
+                4, 0, 0, 0, // fact chunk size
+            ])?;
+            self.fact = Some(self.file.stream_position()?);
+            // number of frames
+            self.file.write_all(&[0, 0, 0, 0])?;
+        }
+
+        self.file.write_all(&[100, 97, 116, 97])?; // data
+        self.data = self.file.stream_position()?;
+        // nframes * nchannels * bytes / frame
+        self.file.write_all(&[0, 0, 0, 0])?;
         Ok(())
     }
     /// write frames and shift position
-    pub fn write(&mut self, waveform: Vec<i16>) -> Result<()> {
-        self.file.write(unsafe {
-            from_raw_parts(waveform.as_ptr() as *const u8, waveform.len() * size_of::<i16>())
-        })?;
-        Ok(())
+    pub fn write(&mut self, waveform: Vec<f64>) -> Result<()> {
+        let mut bytes = Vec::with_capacity(waveform.len() * self.format.width() as usize / 8);
+        waveform.iter().for_each(|&y| self.format.encode(y, &mut bytes));
+        self.file.write_all(&bytes)
     }
     /// go back and write file size
     pub fn finish(&mut self) -> Result<()> {
         let size: u64 = self.file.metadata()?.len();
+        let data = size - self.data - 4;
         self.file.seek(SeekFrom::Start(4))?;
-        self.file.write(&unsafe { transmute(size as u32) } as &[u8; 4])?;
-        self.file.seek(SeekFrom::Start(40))?;
-        self.file.write(&unsafe { transmute((size - 36) as u32) } as &[u8; 4])?;
+        self.file.write_all(&((size - 8) as u32).to_le_bytes())?;
+        if let Some(fact) = self.fact {
+            self.file.seek(SeekFrom::Start(fact))?;
+            self.file.write_all(&((data / self.block_align as u64) as u32).to_le_bytes())?;
+        }
+        self.file.seek(SeekFrom::Start(self.data))?;
+        self.file.write_all(&(data as u32).to_le_bytes())?;
 
         Ok(())
     }